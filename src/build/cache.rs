@@ -0,0 +1,325 @@
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::{BufReader, BufWriter},
+	path::PathBuf,
+	sync::Mutex,
+};
+
+use genpdf::style::{Color, Style, StyledString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::highlight::Span;
+
+/// Name of the cache file in the build directory
+const CACHE_FILE: &str = "highlight-cache.json";
+/// Version of the cache file format. Bumped when [CachedStyle] changes, so older files are ignored
+const CACHE_FORMAT: u32 = 1;
+
+/// On-disk cache of highlighted code. This is kept in the build directory so that unchanged code
+/// blocks aren't highlighted again on the next build, which is especially slow with highlight.js\
+/// Entries are keyed by a hash of the highlighter and theme (the fingerprint), the language, and
+/// the source. Only entries used in a build are saved, so old entries don't build up\
+/// The cache is shared between the threads highlighting chapters, so entries are behind a lock
+#[derive(Default)]
+pub struct HighlightCache {
+	/// Cache file path. `None` if the cache isn't used
+	path: Option<PathBuf>,
+	/// Hash of the highlighter and theme
+	fingerprint: String,
+	entries: Mutex<Entries>,
+}
+
+/// Cache entries
+#[derive(Default)]
+struct Entries {
+	/// Entries from the previous build
+	old: HashMap<String, Lines>,
+	/// Entries used in this build
+	new: HashMap<String, Lines>,
+}
+
+/// Cache file contents
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+	/// Version of mdbook-compress that wrote the cache. The cache is ignored if it doesn't match
+	version: String,
+	/// Cache file format. Files from before the format was recorded are read as 0
+	#[serde(default)]
+	format: u32,
+	entries: HashMap<String, Lines>,
+}
+
+/// Cached lines of styled text
+type Lines = Vec<Vec<(String, CachedStyle)>>;
+
+/// Serializable subset of a [Style] used by highlighters
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct CachedStyle {
+	colour: Option<CachedColour>,
+	background: Option<CachedColour>,
+	bold: bool,
+	italic: bool,
+}
+
+/// Serializable [Color]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+enum CachedColour {
+	Rgb(u8, u8, u8),
+	Cmyk(u8, u8, u8, u8),
+	Greyscale(u8),
+}
+
+impl HighlightCache {
+	/// Load the cache from a build directory. `parts` identify the highlighter and theme, and are
+	/// hashed into the fingerprint used in every key
+	pub fn load(dir: PathBuf, parts: &[&[u8]]) -> Self {
+		let path = dir.join(CACHE_FILE);
+		let old = match File::open(&path) {
+			Ok(f) => match serde_json::from_reader::<_, CacheFile>(BufReader::new(f)) {
+				Ok(cache) if cache.version == env!("CARGO_PKG_VERSION") && cache.format == CACHE_FORMAT => {
+					cache.entries
+				}
+				Ok(_) => HashMap::new(),
+				Err(e) => {
+					println!("Unable to read highlight cache, ignoring it: {}", e);
+					HashMap::new()
+				}
+			},
+			Err(_) => HashMap::new(),
+		};
+		Self {
+			path: Some(path),
+			fingerprint: hash(parts),
+			entries: Mutex::new(Entries {
+				old,
+				new: HashMap::new(),
+			}),
+		}
+	}
+
+	/// Get highlighted code from the cache, or highlight it with the given function and cache it.
+	/// The lock isn't held while highlighting, so other threads can use the cache at the same time
+	pub fn get_or_insert_with<F: FnOnce() -> Vec<Vec<Span>>>(&self, language: &str, src: &str, f: F) -> Vec<Vec<Span>> {
+		let key = hash(&[self.fingerprint.as_bytes(), language.as_bytes(), src.as_bytes()]);
+		{
+			let mut entries = self.entries.lock().unwrap();
+			if let Some(lines) = entries.new.get(&key) {
+				return from_cached(lines)
+			}
+			if let Some(lines) = entries.old.remove(&key) {
+				let out = from_cached(&lines);
+				entries.new.insert(key, lines);
+				return out
+			}
+		}
+		let out = f();
+		self.entries.lock().unwrap().new.insert(key, to_cached(&out));
+		out
+	}
+
+	/// Write the entries used in this build to the cache file. Does nothing if the cache wasn't
+	/// loaded
+	pub fn save(&self) {
+		let Some(path) = &self.path else { return };
+		let cache = CacheFile {
+			version: env!("CARGO_PKG_VERSION").to_string(),
+			format: CACHE_FORMAT,
+			entries: self.entries.lock().unwrap().new.clone(),
+		};
+		if let Some(dir) = path.parent() {
+			let _ = std::fs::create_dir_all(dir);
+		}
+		let res = File::create(path)
+			.map_err(|e| e.to_string())
+			.and_then(|f| serde_json::to_writer(BufWriter::new(f), &cache).map_err(|e| e.to_string()));
+		if let Err(e) = res {
+			println!("Unable to write highlight cache: {}", e)
+		}
+	}
+}
+
+/// SHA-256 hash of some byte strings as hex. Each part is length prefixed so that parts can't run
+/// into each other
+fn hash(parts: &[&[u8]]) -> String {
+	let mut hasher = Sha256::new();
+	for part in parts {
+		hasher.update((part.len() as u64).to_le_bytes());
+		hasher.update(part);
+	}
+	hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn to_cached(lines: &[Vec<Span>]) -> Lines {
+	lines
+		.iter()
+		.map(|line| {
+			line.iter()
+				.map(|span| {
+					let style = CachedStyle {
+						colour: span.s.style.color().map(CachedColour::from),
+						background: span.background.map(CachedColour::from),
+						bold: span.s.style.is_bold(),
+						italic: span.s.style.is_italic(),
+					};
+					(span.s.s.clone(), style)
+				})
+				.collect()
+		})
+		.collect()
+}
+
+fn from_cached(lines: &Lines) -> Vec<Vec<Span>> {
+	lines
+		.iter()
+		.map(|line| {
+			line.iter()
+				.map(|(s, cached)| {
+					let mut style = Style::new();
+					if let Some(colour) = cached.colour {
+						style.set_color(colour.into())
+					}
+					if cached.bold {
+						style.set_bold()
+					}
+					if cached.italic {
+						style.set_italic()
+					}
+					Span {
+						s: StyledString::new(s.clone(), style),
+						background: cached.background.map(Color::from),
+					}
+				})
+				.collect()
+		})
+		.collect()
+}
+
+impl From<Color> for CachedColour {
+	fn from(colour: Color) -> Self {
+		match colour {
+			Color::Rgb(r, g, b) => Self::Rgb(r, g, b),
+			Color::Cmyk(c, m, y, k) => Self::Cmyk(c, m, y, k),
+			Color::Greyscale(g) => Self::Greyscale(g),
+		}
+	}
+}
+
+impl From<CachedColour> for Color {
+	fn from(colour: CachedColour) -> Self {
+		match colour {
+			CachedColour::Rgb(r, g, b) => Self::Rgb(r, g, b),
+			CachedColour::Cmyk(c, m, y, k) => Self::Cmyk(c, m, y, k),
+			CachedColour::Greyscale(g) => Self::Greyscale(g),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Empty build directory for a test
+	fn build_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("mdbook-compress-cache-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		dir
+	}
+
+	fn highlighted() -> Vec<Vec<Span>> {
+		let mut span = Span::new("fn", Style::new().bold().italic().with_color(Color::Rgb(1, 2, 3)));
+		span.background = Some(Color::Cmyk(4, 5, 6, 7));
+		vec![vec![span, Span::new(" main", Style::new())], vec![]]
+	}
+
+	/// Highlight some code through a cache, returning whether the highlighter was called
+	fn highlight(cache: &HighlightCache, src: &str) -> bool {
+		let mut called = false;
+		cache.get_or_insert_with("rust", src, || {
+			called = true;
+			highlighted()
+		});
+		called
+	}
+
+	#[test]
+	fn round_trip() {
+		let dir = build_dir("round-trip");
+		let cache = HighlightCache::load(dir.clone(), &[b"syntect", b"theme"]);
+		assert!(highlight(&cache, "fn main"));
+		assert!(!highlight(&cache, "fn main"));
+		cache.save();
+		let cache = HighlightCache::load(dir.clone(), &[b"syntect", b"theme"]);
+		let lines = cache.get_or_insert_with("rust", "fn main", || panic!("not cached"));
+		let expected = highlighted();
+		assert_eq!(lines.len(), expected.len());
+		for (line, expected) in lines.iter().flatten().zip(expected.iter().flatten()) {
+			assert_eq!(line.s.s, expected.s.s);
+			assert_eq!(line.s.style, expected.s.style);
+			assert_eq!(line.background, expected.background);
+		}
+		// a different language or source isn't a hit
+		assert!(cache.get_or_insert_with("toml", "fn main", Vec::new).is_empty());
+		assert!(highlight(&cache, "fn other"));
+		let _ = std::fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn invalidation() {
+		let dir = build_dir("invalidation");
+		let cache = HighlightCache::load(dir.clone(), &[b"syntect", b"theme"]);
+		highlight(&cache, "fn main");
+		cache.save();
+		// another highlighter or theme changes the fingerprint
+		assert!(highlight(
+			&HighlightCache::load(dir.clone(), &[b"syntect", b"other"]),
+			"fn main"
+		));
+		// parts are length prefixed, so moving bytes between them changes the fingerprint too
+		assert!(highlight(
+			&HighlightCache::load(dir.clone(), &[b"syntec", b"ttheme"]),
+			"fn main"
+		));
+		// files from another version or format are ignored
+		let path = dir.join(CACHE_FILE);
+		let file = std::fs::read_to_string(&path).unwrap();
+		let mut value = serde_json::from_str::<serde_json::Value>(&file).unwrap();
+		value["version"] = "0.0.0".into();
+		std::fs::write(&path, value.to_string()).unwrap();
+		assert!(highlight(
+			&HighlightCache::load(dir.clone(), &[b"syntect", b"theme"]),
+			"fn main"
+		));
+		let mut value = serde_json::from_str::<serde_json::Value>(&file).unwrap();
+		value.as_object_mut().unwrap().remove("format");
+		std::fs::write(&path, value.to_string()).unwrap();
+		assert!(highlight(
+			&HighlightCache::load(dir.clone(), &[b"syntect", b"theme"]),
+			"fn main"
+		));
+		// as are unreadable files
+		std::fs::write(&path, "{").unwrap();
+		assert!(highlight(
+			&HighlightCache::load(dir.clone(), &[b"syntect", b"theme"]),
+			"fn main"
+		));
+		let _ = std::fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn unused_entries_dropped() {
+		let dir = build_dir("unused");
+		let cache = HighlightCache::load(dir.clone(), &[b"syntect"]);
+		highlight(&cache, "a");
+		highlight(&cache, "b");
+		cache.save();
+		let cache = HighlightCache::load(dir.clone(), &[b"syntect"]);
+		assert!(!highlight(&cache, "a"));
+		cache.save();
+		let cache = HighlightCache::load(dir.clone(), &[b"syntect"]);
+		assert!(!highlight(&cache, "a"));
+		assert!(highlight(&cache, "b"));
+		let _ = std::fs::remove_dir_all(dir);
+	}
+}
@@ -0,0 +1,54 @@
+use genpdf::{
+	error::Error,
+	render::Area,
+	style::{Color, Style},
+	Context, Element, Mm, Position, RenderResult, Size,
+};
+
+use crate::build::{draw::fill_rect, flow::Measure};
+
+/// Width of the bar down the left side of a callout in mm
+const BAR_WIDTH: f64 = 1.0;
+/// Space between the bar and the contents in mm
+const GAP: f64 = 3.0;
+
+/// Callout box, like mdBook's warnings. The contents are indented with a coloured bar down the
+/// left side, which continues over page breaks
+pub struct Callout<E: Element> {
+	element: E,
+	colour: Color,
+}
+
+impl<E: Element> Callout<E> {
+	/// Create a new callout around an element
+	pub fn new(element: E, colour: Color) -> Self { Self { element, colour } }
+}
+
+impl<E: Element> Element for Callout<E> {
+	fn render(&mut self, context: &Context, area: Area<'_>, style: Style) -> Result<RenderResult, Error> {
+		let mut inner = area.clone();
+		inner.add_offset(Position::new(BAR_WIDTH + GAP, 0));
+		inner.set_width(area.size().width - Mm::from(BAR_WIDTH + GAP));
+		let mut result = self.element.render(context, inner, style)?;
+		// the bar is drawn after the contents because their height isn't known before. It doesn't
+		// overlap them, so it doesn't cover any text
+		fill_rect(
+			&area,
+			Position::new(0, 0),
+			Size::new(BAR_WIDTH, result.size.height),
+			self.colour,
+		);
+		result.size.width += Mm::from(BAR_WIDTH + GAP);
+		Ok(result)
+	}
+}
+
+impl<E: Measure> Measure for Callout<E> {
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		self.element.height(width - Mm::from(BAR_WIDTH + GAP), context, style)
+	}
+
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		self.element.lead(width - Mm::from(BAR_WIDTH + GAP), context, style)
+	}
+}
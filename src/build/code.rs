@@ -0,0 +1,604 @@
+use std::ops::RangeInclusive;
+
+use genpdf::{
+	error::{Error, ErrorKind},
+	render::Area,
+	style::{Color, Style, StyledString},
+	Context, Element, Mm, Position, RenderResult, Size,
+};
+
+use crate::{
+	build::{
+		draw::{fill_rect, font_metrics},
+		flow::Measure,
+		MIN_SHRINK_SIZE,
+	},
+	config::Overflow,
+	highlight::Span,
+};
+
+/// Line of a `diff-<language>` code block
+pub enum DiffLine {
+	/// Added line (`+`)
+	Added,
+	/// Removed line (`-`)
+	Removed,
+	/// Unchanged line (` `)
+	Context,
+	/// Any other line, like a hunk header (`@@ -1,3 +1,4 @@`). These aren't highlighted
+	Other(String),
+}
+
+/// Split the diff markers off the lines of a `diff-<language>` code block. Returns the code
+/// without markers, so that it can be highlighted as the inner language, and the kind of each line
+pub fn split_diff(src: &str) -> (String, Vec<DiffLine>) {
+	let mut code = String::new();
+	let mut kinds = Vec::new();
+	for line in src.lines() {
+		let (kind, rest) = match line.chars().next() {
+			Some('+') => (DiffLine::Added, &line[1..]),
+			Some('-') => (DiffLine::Removed, &line[1..]),
+			Some(' ') => (DiffLine::Context, &line[1..]),
+			None => (DiffLine::Context, ""),
+			Some(_) => (DiffLine::Other(line.to_string()), ""),
+		};
+		code.push_str(rest);
+		code.push('\n');
+		kinds.push(kind)
+	}
+	(code, kinds)
+}
+
+/// Put diff markers back at the start of highlighted lines. Returns the background colour for
+/// each line
+pub fn mark_diff(lines: &mut Vec<Vec<Span>>, kinds: Vec<DiffLine>, added: Color, removed: Color) -> Vec<Option<Color>> {
+	lines.resize(kinds.len(), Vec::new());
+	kinds
+		.into_iter()
+		.zip(lines.iter_mut())
+		.map(|(kind, line)| {
+			let (marker, colour, background) = match kind {
+				DiffLine::Added => ("+", Color::Rgb(34, 134, 58), Some(added)),
+				DiffLine::Removed => ("-", Color::Rgb(179, 29, 40), Some(removed)),
+				DiffLine::Context => (" ", Color::Greyscale(150), None),
+				DiffLine::Other(text) => {
+					*line = vec![Span::new(text, Color::Greyscale(120))];
+					return None
+				}
+			};
+			line.insert(0, Span::new(marker, colour));
+			background
+		})
+		.collect()
+}
+
+/// Expand tabs in a line of spans to spaces, with tab stops every `width` characters. Columns are
+/// counted across the whole line, so a tab in one span lines up with the text before it
+pub fn expand_tabs(line: &mut [Span], width: usize) {
+	let width = width.max(1);
+	let mut column = 0;
+	for s in line.iter_mut().map(|span| &mut span.s) {
+		if !s.s.contains('\t') {
+			column += s.s.chars().count();
+			continue
+		}
+		let mut expanded = String::with_capacity(s.s.len());
+		for c in s.s.chars() {
+			if c == '\t' {
+				let spaces = width - column % width;
+				expanded.push_str(&" ".repeat(spaces));
+				column += spaces
+			} else {
+				expanded.push(c);
+				column += 1
+			}
+		}
+		s.s = expanded
+	}
+}
+
+/// Code block element. Lines are laid out by the element itself so that backgrounds can be drawn
+/// before the text. Lines that are too wide for the page are handled by the [Overflow] mode\
+/// When a block is split over multiple pages, each part gets its own background and side borders,
+/// with the top and bottom padding and borders only at the start and end of the block\
+/// Spans with their own background (from the highlighting theme) have it drawn behind their text
+pub struct CodeBlock {
+	/// Code lines
+	lines: Vec<Vec<Span>>,
+	/// Line number of the first line, if lines should be numbered
+	line_start: Option<usize>,
+	/// Highlighted line ranges. These are relative to the start of the block starting at 1
+	highlight: Vec<RangeInclusive<usize>>,
+	/// Background colour for highlighted lines
+	highlight_colour: Color,
+	/// Background colours for each line (used for diffs). Highlighted lines take precedence
+	line_backgrounds: Vec<Option<Color>>,
+	/// Block background colour
+	background: Option<Color>,
+	/// Block border colour
+	border: Option<Color>,
+	/// Padding between the block edges and the text
+	padding: Mm,
+	/// How lines that are too long are handled
+	overflow: Overflow,
+	/// Style of the code
+	style: Style,
+	/// Font size set when shrinking the block to fit the longest line
+	font_size: Option<u8>,
+	/// Laid out rows. Filled on the first call to render
+	rows: Vec<Row>,
+	/// Index of the next row to render
+	render_idx: usize,
+	/// Set when nothing fitted and the block was moved to the next page. If nothing fits there
+	/// either, the block can't be rendered at all
+	deferred: bool,
+}
+
+/// A single row of a code block. Long lines are split over multiple rows
+struct Row {
+	/// Index of the line the row is from
+	line: usize,
+	/// If this row continues a line from the previous row
+	continuation: bool,
+	/// Row contents
+	spans: Vec<Span>,
+}
+
+impl CodeBlock {
+	/// Create a new code block from lines of spans
+	pub fn new(lines: Vec<Vec<Span>>) -> Self {
+		Self {
+			lines,
+			line_start: None,
+			highlight: Vec::new(),
+			highlight_colour: Color::Rgb(255, 244, 184),
+			line_backgrounds: Vec::new(),
+			background: None,
+			border: None,
+			padding: Mm::from(0),
+			overflow: Overflow::default(),
+			style: Style::new(),
+			font_size: None,
+			rows: Vec::new(),
+			render_idx: 0,
+			deferred: false,
+		}
+	}
+
+	/// Number the lines of the block, starting at the given number
+	pub fn with_line_numbers(mut self, start: Option<usize>) -> Self {
+		self.line_start = start;
+		self
+	}
+
+	/// Highlight the given lines with a background colour
+	pub fn with_highlight(mut self, lines: Vec<RangeInclusive<usize>>, colour: Color) -> Self {
+		self.highlight = lines;
+		self.highlight_colour = colour;
+		self
+	}
+
+	/// Set background colours for each line
+	pub fn with_line_backgrounds(mut self, backgrounds: Vec<Option<Color>>) -> Self {
+		self.line_backgrounds = backgrounds;
+		self
+	}
+
+	/// Set the block background colour, border colour, and padding in millimeters
+	pub fn with_box(mut self, background: Option<Color>, border: Option<Color>, padding: f64) -> Self {
+		self.background = background;
+		self.border = border;
+		self.padding = Mm::from(padding);
+		self
+	}
+
+	/// Expand tabs to spaces, with tab stops every `width` characters
+	pub fn with_tab_width(mut self, width: usize) -> Self {
+		for line in self.lines.iter_mut() {
+			expand_tabs(line, width)
+		}
+		self
+	}
+
+	/// Set the style of the code
+	pub fn with_style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	/// Set how lines that are too long for the page are handled
+	pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+		self.overflow = overflow;
+		self
+	}
+
+	/// Width in characters of the line number gutter, excluding the separating space
+	fn gutter_chars(&self) -> usize {
+		self.line_start
+			.map(|start| (start + self.lines.len().max(1) - 1).to_string().len())
+			.unwrap_or(0)
+	}
+
+	/// Find the font size needed for the longest line and the line number gutter to fit within
+	/// the given width. Returns `None` if the block already fits
+	fn shrink_to_fit(&self, width: Mm, context: &Context, style: Style) -> Option<u8> {
+		let gutter = style.str_width(&context.font_cache, &" ".repeat(self.gutter_chars() + 2));
+		let longest = self
+			.lines
+			.iter()
+			.map(|line| line_width(line, context, style))
+			.fold(Mm::from(0), |a, b| if b > a { b } else { a });
+		let needed = if self.line_start.is_some() {
+			gutter + longest
+		} else {
+			longest
+		};
+		if needed <= width {
+			return None
+		}
+		let scale: f64 = f64::from(width) / f64::from(needed);
+		Some(((style.font_size() as f64 * scale).floor() as u8).max(MIN_SHRINK_SIZE))
+	}
+
+	/// Get the style of the block, shrunk if needed. The lines are laid out into rows the first time
+	/// this is called
+	fn prepare(&mut self, width: Mm, context: &Context, style: Style) -> Style {
+		let style = style.and(self.style);
+		if self.render_idx > 0 || !self.rows.is_empty() {
+			return match self.font_size {
+				Some(size) => style.with_font_size(size),
+				None => style,
+			}
+		}
+		if self.overflow == Overflow::shrink {
+			self.font_size = self.shrink_to_fit(width - self.padding * 2.0, context, style)
+		}
+		let style = match self.font_size {
+			Some(size) => style.with_font_size(size),
+			None => style,
+		};
+		let gutter = if self.gutter_chars() > 0 {
+			style.str_width(&context.font_cache, &" ".repeat(self.gutter_chars() + 2))
+		} else {
+			Mm::from(0)
+		};
+		self.layout(width - self.padding * 2.0 - gutter, context, style);
+		style
+	}
+
+	/// Split all the lines into rows that fit within the given width
+	fn layout(&mut self, width: Mm, context: &Context, style: Style) {
+		let marker = continuation_marker(context, style);
+		for (line, spans) in self.lines.iter().enumerate() {
+			let rows = match self.overflow {
+				Overflow::clip => vec![clip(spans, width, context, style)],
+				Overflow::wrap | Overflow::shrink => wrap(spans, width, marker, context, style),
+			};
+			for (i, spans) in rows.into_iter().enumerate() {
+				self.rows.push(Row {
+					line,
+					continuation: i > 0,
+					spans,
+				})
+			}
+		}
+	}
+}
+
+impl Element for CodeBlock {
+	fn render(&mut self, context: &Context, area: Area<'_>, style: Style) -> Result<RenderResult, Error> {
+		let mut result = RenderResult::default();
+		let width = area.size().width;
+		let first = self.render_idx == 0;
+		let style = self.prepare(width, context, style);
+		let line_height = style.line_height(&context.font_cache);
+		// text is centred vertically in each row so that row backgrounds line up with the text
+		let (ascent, descent) = font_metrics(context, style);
+		let row_offset = (line_height - ascent - descent) / 2.0 - descent;
+		let gutter_chars = self.gutter_chars();
+		let gutter = if gutter_chars > 0 {
+			style.str_width(&context.font_cache, &" ".repeat(gutter_chars + 2))
+		} else {
+			Mm::from(0)
+		};
+		// find how many rows fit in this area
+		let top = if first { self.padding } else { Mm::from(0) };
+		let mut end = self.render_idx;
+		while end < self.rows.len() && top + line_height * (end - self.render_idx + 1) as f64 <= area.size().height {
+			end += 1
+		}
+		if end == self.rows.len() && end == self.render_idx {
+			return Ok(result)
+		}
+		if end == self.render_idx {
+			// a block that's been started, or was already moved on, is at the top of a new page
+			if self.deferred || !first {
+				return Err(Error::new(
+					"Code block padding and line are too tall for the page",
+					ErrorKind::PageSizeExceeded,
+				))
+			}
+			self.deferred = true;
+			result.has_more = true;
+			return Ok(result)
+		}
+		let last = end == self.rows.len();
+		let rows_height = top + line_height * (end - self.render_idx) as f64;
+		let height = if last {
+			let space = area.size().height - rows_height;
+			rows_height + if space < self.padding { space } else { self.padding }
+		} else {
+			rows_height
+		};
+		if let Some(background) = self.background {
+			fill_rect(&area, Position::default(), Size::new(width, height), background)
+		}
+		for (i, row) in self.rows[self.render_idx..end].iter().enumerate() {
+			let y = top + line_height * i as f64;
+			let band = if self.highlight.iter().any(|r| r.contains(&(row.line + 1))) {
+				Some(self.highlight_colour)
+			} else {
+				self.line_backgrounds.get(row.line).copied().flatten()
+			};
+			if let Some(band) = band {
+				fill_rect(&area, Position::new(0, y), Size::new(width, line_height), band)
+			}
+			// span backgrounds go over the row background, and under the text
+			let mut x = self.padding + gutter;
+			for span in &row.spans {
+				let w = style.and(span.s.style).str_width(&context.font_cache, &span.s.s);
+				if let Some(background) = span.background {
+					fill_rect(&area, Position::new(x, y), Size::new(w, line_height), background)
+				}
+				x += w
+			}
+			if let Some(mut section) =
+				area.text_section(&context.font_cache, Position::new(self.padding, y + row_offset), style)
+			{
+				if let Some(start) = self.line_start {
+					let number = if row.continuation {
+						" ".repeat(gutter_chars + 2)
+					} else {
+						format!("{:>width$}  ", start + row.line, width = gutter_chars)
+					};
+					section.print_str(number, style.and(Color::Greyscale(150)))?;
+				}
+				for span in &row.spans {
+					section.print_str(&span.s.s, style.and(span.s.style))?;
+				}
+			}
+		}
+		if let Some(border) = self.border {
+			let border = Style::new().with_color(border);
+			let corners = [
+				Position::default(),
+				Position::new(width, 0),
+				Position::new(width, height),
+				Position::new(0, height),
+			];
+			area.draw_line(vec![corners[0], corners[3]], border);
+			area.draw_line(vec![corners[1], corners[2]], border);
+			if first {
+				area.draw_line(vec![corners[0], corners[1]], border);
+			}
+			if last {
+				area.draw_line(vec![corners[3], corners[2]], border);
+			}
+		}
+		self.render_idx = end;
+		result.size = Size::new(width, height);
+		result.has_more = !last;
+		Ok(result)
+	}
+}
+
+impl Measure for CodeBlock {
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		let style = self.prepare(width, context, style);
+		let top = if self.render_idx == 0 {
+			self.padding
+		} else {
+			Mm::from(0)
+		};
+		top + style.line_height(&context.font_cache) * (self.rows.len() - self.render_idx) as f64 + self.padding
+	}
+
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		let style = self.prepare(width, context, style);
+		let top = if self.render_idx == 0 {
+			self.padding
+		} else {
+			Mm::from(0)
+		};
+		top + style.line_height(&context.font_cache)
+	}
+}
+
+/// Width of a line of spans
+fn line_width(line: &[Span], context: &Context, style: Style) -> Mm {
+	line.iter().fold(Mm::from(0), |w, span| {
+		w + style.and(span.s.style).str_width(&context.font_cache, &span.s.s)
+	})
+}
+
+/// Marker put at the start of continuation rows. Uses `↪` if the font has it, or `→` if not
+fn continuation_marker(context: &Context, style: Style) -> &'static str {
+	let font = style.font(&context.font_cache);
+	if font.glyph_ids(&context.font_cache, "↪".chars()).first() != Some(&0) {
+		"↪ "
+	} else {
+		"→ "
+	}
+}
+
+/// Split a line into rows that fit within the given width. Rows are broken after whitespace or
+/// between differently styled spans (which are usually separate tokens) where possible, and
+/// between characters otherwise. Continuation rows are indented to match the line and start
+/// with a marker. Empty lines produce a single empty row
+fn wrap(line: &[Span], width: Mm, marker: &str, context: &Context, style: Style) -> Vec<Vec<Span>> {
+	let chars = line
+		.iter()
+		.flat_map(|span| span.s.s.chars().map(move |c| (c, span.s.style, span.background)))
+		.collect::<Vec<_>>();
+	let widths = chars
+		.iter()
+		.map(|(c, s, _)| style.and(*s).char_width(&context.font_cache, *c))
+		.collect::<Vec<_>>();
+	let mut indent = " ".repeat(chars.iter().take_while(|(c, ..)| *c == ' ').count()) + marker;
+	if style.str_width(&context.font_cache, &indent) > width / 2.0 {
+		indent = marker.to_string()
+	}
+	let indent_width = style.str_width(&context.font_cache, &indent);
+	let mut rows = Vec::new();
+	let mut start = 0;
+	loop {
+		let available = if rows.is_empty() { width } else { width - indent_width };
+		let mut end = start;
+		let mut x = Mm::from(0);
+		let mut text = false;
+		let mut brk = None;
+		while end < chars.len() && (end == start || x + widths[end] <= available) {
+			x += widths[end];
+			text |= !chars[end].0.is_whitespace();
+			end += 1;
+			if text
+				&& end < chars.len()
+				&& ((chars[end - 1].0.is_whitespace() && !chars[end].0.is_whitespace())
+					|| (chars[end - 1].1, chars[end - 1].2) != (chars[end].1, chars[end].2))
+			{
+				brk = Some(end)
+			}
+		}
+		if end < chars.len() {
+			end = brk.unwrap_or(end)
+		}
+		let mut row = Vec::new();
+		if !rows.is_empty() {
+			row.push(Span::new(indent.clone(), Color::Greyscale(150)))
+		}
+		row.extend(group(&chars[start..end]));
+		rows.push(row);
+		start = end;
+		if start >= chars.len() {
+			break
+		}
+	}
+	rows
+}
+
+/// Cut a line to fit within the given width, ending it with an ellipsis if anything was removed
+fn clip(line: &[Span], width: Mm, context: &Context, style: Style) -> Vec<Span> {
+	if line_width(line, context, style) <= width {
+		return line.to_vec()
+	}
+	let available = width - style.char_width(&context.font_cache, '…');
+	let mut chars = Vec::new();
+	let mut x = Mm::from(0);
+	'outer: for span in line {
+		for c in span.s.s.chars() {
+			x += style.and(span.s.style).char_width(&context.font_cache, c);
+			if x > available {
+				break 'outer
+			}
+			chars.push((c, span.s.style, span.background))
+		}
+	}
+	let mut out = group(&chars);
+	out.push(Span::new("…", Color::Greyscale(150)));
+	out
+}
+
+/// Group styled characters back into spans
+fn group(chars: &[(char, Style, Option<Color>)]) -> Vec<Span> {
+	let mut out: Vec<Span> = Vec::new();
+	for (c, style, background) in chars {
+		match out.last_mut() {
+			Some(span) if span.s.style == *style && span.background == *background => span.s.s.push(*c),
+			_ => out.push(Span {
+				s: StyledString::new(c.to_string(), *style),
+				background: *background,
+			}),
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::build::{
+		flow::{Flow, Keep},
+		testing::render,
+		text::TextBlock,
+	};
+
+	/// Chapter with a paragraph and a short code block
+	fn chapter(padding: f64) -> Flow {
+		let mut flow = Flow::new(120.0);
+		let mut para = TextBlock::new();
+		para.push_styled("Some text before the code", Style::new());
+		flow.push(para, (0.0, 2.0), Keep::Split);
+		let code = CodeBlock::new(vec![vec![Span::new("let x = 1;", Style::new())]]).with_box(
+			Some(Color::Greyscale(240)),
+			None,
+			padding,
+		);
+		flow.push(code, (0.0, 2.0), Keep::Together);
+		flow
+	}
+
+	fn line(parts: &[&str]) -> Vec<Span> { parts.iter().map(|s| Span::new(*s, Style::new())).collect() }
+
+	fn text(line: &[Span]) -> Vec<&str> { line.iter().map(|span| span.s.s.as_str()).collect() }
+
+	#[test]
+	fn tabs() {
+		let mut spans = line(&["\tx", "ab\tc\t"]);
+		expand_tabs(&mut spans, 4);
+		assert_eq!(text(&spans), vec!["    x", "ab c   "]);
+		// columns carry over from the spans before
+		let mut spans = line(&["abc", "\td"]);
+		expand_tabs(&mut spans, 4);
+		assert_eq!(text(&spans), vec!["abc", " d"]);
+		let mut spans = line(&["é\t"]);
+		expand_tabs(&mut spans, 2);
+		assert_eq!(text(&spans), vec!["é "]);
+		// a width of 0 is read as 1
+		let mut spans = line(&["a\tb"]);
+		expand_tabs(&mut spans, 0);
+		assert_eq!(text(&spans), vec!["a b"]);
+	}
+
+	#[test]
+	fn diff_markers() {
+		let (code, kinds) = split_diff("@@ -1 +1,2 @@\n-let x = 1;\n+let x = 2;\n\n keep();");
+		assert_eq!(code, "\nlet x = 1;\nlet x = 2;\n\nkeep();\n");
+		assert!(matches!(
+			kinds.as_slice(),
+			[
+				DiffLine::Other(header),
+				DiffLine::Removed,
+				DiffLine::Added,
+				DiffLine::Context,
+				DiffLine::Context
+			] if header == "@@ -1 +1,2 @@"
+		));
+		// highlighting can drop the last lines, which are filled back in
+		let mut lines = vec![line(&[""]), line(&["let x = 1;"]), line(&["let x = 2;"])];
+		let (added, removed) = (Color::Rgb(0, 255, 0), Color::Rgb(255, 0, 0));
+		let backgrounds = mark_diff(&mut lines, kinds, added, removed);
+		assert_eq!(backgrounds, vec![None, Some(removed), Some(added), None, None]);
+		assert_eq!(
+			lines.iter().map(|line| text(line).concat()).collect::<Vec<_>>(),
+			vec!["@@ -1 +1,2 @@", "-let x = 1;", "+let x = 2;", " ", " "]
+		);
+	}
+
+	#[test]
+	fn padding_taller_than_page() {
+		assert!(render(chapter(2.0), (100.0, 100.0)).is_ok());
+		// the padding leaves no room for a line on any page, which fails instead of adding empty
+		// pages forever
+		assert!(render(chapter(60.0), (100.0, 100.0)).is_err());
+	}
+}
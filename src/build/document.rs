@@ -2,20 +2,22 @@ use std::{fs::File, io::BufReader};
 
 use genpdf::{
 	elements,
-	error::Error,
+	error::{Error, ErrorKind},
 	fonts::{Font, FontData, FontFamily},
 	style::Style,
 	Alignment, Document, Element as _, SimplePageDecorator,
 };
 use mdbook::{book::Chapter, renderer::RenderContext, BookItem};
+use rayon::prelude::*;
 use syntect::{
-	highlighting::ThemeSet,
-	parsing::{SyntaxSet, SyntaxSetBuilder},
+	highlighting::{Theme, ThemeSet},
+	parsing::SyntaxSet,
 };
 
 use crate::{
-	config::{Config, Highlight},
-	highlight::util::StyleElement,
+	build::{cache::HighlightCache, print, sections},
+	config::{Config, Highlight, PrintMode},
+	highlight, ir,
 };
 
 /// Main struct used for PDF generation
@@ -30,6 +32,8 @@ pub struct Generator {
 	pub monospace: FontFamily<Font>,
 	/// Document title. Included in other places, but I'm lazy and made it easier to access
 	pub title: String,
+	/// Highlighted code cache. Loaded when building
+	pub cache: HighlightCache,
 }
 
 // Required file contents
@@ -44,7 +48,10 @@ impl Generator {
 	/// This will initialise the PDF with only fonts and configs. [Generator::configure] is then
 	/// called which will initialise the first page (title, subtitle, SUMMARY.md) and the page
 	/// settings (decorator, size, etc.)
-	pub fn new(rc: RenderContext, pdf_opts: Config) -> Self {
+	pub fn new(rc: RenderContext, mut pdf_opts: Config) -> Self {
+		if pdf_opts.print_mode == PrintMode::grayscale {
+			print::config(&mut pdf_opts)
+		}
 		let mut fonts = FontFamily {
 			regular: FontData::new(OPEN_SANS.to_vec(), None).unwrap(),
 			bold: FontData::new(OPEN_SANS_BOLD.to_vec(), None).unwrap(),
@@ -79,7 +86,7 @@ impl Generator {
 				}
 			}
 		}
-		let title = rc.config.book.title.clone().unwrap_or(String::new());
+		let title = rc.config.book.title.clone().unwrap_or_default();
 		let mut document = Document::new(fonts);
 		let monospace = document.add_font_family(FontFamily {
 			regular: monospace_raw.clone(),
@@ -93,6 +100,7 @@ impl Generator {
 			document,
 			monospace,
 			title,
+			cache: Default::default(),
 		}
 		.configure()
 	}
@@ -107,104 +115,149 @@ impl Generator {
 		self.document
 			.set_paper_size(self.pdf_opts.page.size.size(self.pdf_opts.page.landscape));
 		let mut decorator = SimplePageDecorator::new();
-		decorator.set_header(|p| {
+		let header_style = self.text_style().with_font_size(10);
+		decorator.set_header(move |p| {
 			let mut layout = elements::LinearLayout::vertical();
 			if p > 1 {
 				layout.push(elements::Paragraph::new(p.to_string()).aligned(Alignment::Center));
 				layout.push(elements::Break::new(1));
 			}
-			layout.styled(Style::new().with_font_size(10))
+			layout.styled(header_style)
 		});
 		decorator.set_margins(self.pdf_opts.page.spacing.margin);
 		self.document.set_page_decorator(decorator);
 		self.document.push(
 			elements::Paragraph::new(self.title.clone())
 				.aligned(Alignment::Center)
-				.styled(Style::new().bold().with_font_size(self.pdf_opts.font_size.title)),
+				.styled(self.text_style().bold().with_font_size(self.pdf_opts.font_size.title)),
 		);
 		if let Some(subtitle) = &self.pdf_opts.subtitle {
 			self.document.push(
 				elements::Paragraph::new(subtitle)
 					.aligned(Alignment::Center)
-					.styled(Style::new().with_font_size(self.pdf_opts.font_size.h4)),
+					.styled(self.text_style().with_font_size(self.pdf_opts.font_size.h4)),
 			);
 		}
 		let mut chapter_map = Vec::new();
 		let mut contents = elements::OrderedList::new();
 		for i in self.config.book.sections.iter() {
-			match i {
-				BookItem::Chapter(c) => {
-					chapter_map.push(ChapterMap::new(c));
-				}
-				_ => {}
+			if let BookItem::Chapter(c) = i {
+				chapter_map.push(ChapterMap::new(c));
 			}
 		}
 		for chapter in chapter_map {
-			chapter.to_list(self.pdf_opts.font_size.text, &mut contents)
+			chapter.into_list(self.pdf_opts.font_size.text, &mut contents)
 		}
 		self.document
-			.push(contents.styled(Style::new().with_font_size(self.pdf_opts.font_size.text)));
+			.push(contents.styled(self.text_style().with_font_size(self.pdf_opts.font_size.text)));
 		self
 	}
 
+	/// Base style of all text, from the theme
+	pub fn text_style(&self) -> Style {
+		match self.pdf_opts.text.colour {
+			Some(colour) => Style::new().with_color(colour.0),
+			None => Style::new(),
+		}
+	}
+
 	/// Build the PDF\
 	/// Appends PDF elements to the document, then writes the generated document, optionally
 	/// returning an error that's handled in the main function
 	pub fn build(mut self) -> Result<(), Error> {
 		// check for highlighting, and custom a highlight_.js file
 		let default_theme = ThemeSet::load_defaults().themes["base16-ocean.light"].clone();
-		let default_theme = default_theme.scopes.into();
+		let theme_dir = self.config.root.join("theme");
 		let mut hl = match self.pdf_opts.highlight {
 			Highlight::all => {
-				if let Ok(custom) = std::fs::read_to_string(self.config.root.join("theme").join("highlight.js")) {
+				if let Ok(custom) = std::fs::read_to_string(theme_dir.join("highlight.js")) {
 					Some(HL::highlight(custom))
 				} else {
-					let mut ss = SyntaxSetBuilder::new();
-					if let Err(e) = ss.add_from_folder(self.config.root.join("theme"), true) {
-						println!("Unable to load syntax files from theme folder: {}", e)
-					};
-					Some(HL::syntect((ss.build(), default_theme)))
+					Some(HL::syntect((
+						highlight::syntect::load_syntaxes(&theme_dir),
+						default_theme,
+					)))
 				}
 			}
-			Highlight::no_node => {
-				let mut ss = SyntaxSet::load_defaults_newlines().into_builder();
-				if let Err(e) = ss.add_from_folder(self.config.root.join("theme"), true) {
-					println!("Unable to load syntax files from theme folder: {}", e)
-				};
-				Some(HL::syntect((ss.build(), default_theme)))
-			}
+			Highlight::no_node => Some(HL::syntect((
+				highlight::syntect::load_syntaxes(&theme_dir),
+				default_theme,
+			))),
 			Highlight::none => None,
 		};
-		if let Some(HL::syntect((ss, _))) = &hl {
-			if let Ok(theme) = File::open(self.config.root.join("theme").join("theme.tmtheme")) {
+		if let Some(HL::syntect((ss, ..))) = &hl {
+			if let Ok(theme) = File::open(theme_dir.join("theme.tmtheme")) {
 				match ThemeSet::load_from_reader(&mut BufReader::new(theme)) {
-					Ok(theme) => hl = Some(HL::syntect((ss.clone(), theme.scopes.into()))),
+					Ok(theme) => hl = Some(HL::syntect((ss.clone(), theme))),
 					Err(e) => {
 						println!("Error loading custom ththeme: {}", e)
 					}
 				}
 			}
 		}
-		for chapter in self.config.clone().book.iter() {
-			if let BookItem::Chapter(chapter) = chapter {
-				self.chapter(&*chapter.content, &hl)
+		// anything that changes highlighting output is part of the cache fingerprint. Without
+		// highlighting nothing is cached, so the cache file isn't touched
+		let fingerprint = match &hl {
+			Some(HL::syntect(_)) => {
+				let mut parts = vec![
+					b"syntect".to_vec(),
+					cfg!(feature = "extended-syntaxes").to_string().into_bytes(),
+					std::fs::read(theme_dir.join("theme.tmtheme")).unwrap_or_default(),
+				];
+				parts.extend(highlight::syntect::syntax_sources(&theme_dir));
+				Some(parts)
 			}
+			Some(HL::highlight(src)) => Some(vec![b"highlight.js".to_vec(), src.as_bytes().to_vec()]),
+			None => None,
+		};
+		if let Some(fingerprint) = fingerprint {
+			self.cache = HighlightCache::load(
+				self.config.destination.clone(),
+				&fingerprint.iter().map(|p| p.as_slice()).collect::<Vec<_>>(),
+			)
 		}
-		match File::create(format!("{}.pdf", self.title)) {
-			Ok(f) => match self.document.render(f) {
-				Ok(_) => Ok(()),
-				Err(e) => Err(e),
-			},
-			Err(e) => Err(Error::new(format!("Unable to write document: {}", e), e)),
+		// chapters are parsed and highlighted in parallel, then added to the document in order
+		let pool = rayon::ThreadPoolBuilder::new()
+			.num_threads(self.pdf_opts.jobs)
+			.build()
+			.map_err(|e| Error::new(format!("Unable to start worker threads: {}", e), ErrorKind::Internal))?;
+		let chapters = self
+			.config
+			.book
+			.iter()
+			.filter_map(|item| match item {
+				BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		let (opts, cache) = (&self.pdf_opts.code, &self.cache);
+		let parsed = pool.install(|| {
+			chapters
+				.par_iter()
+				.map(|chapter| {
+					let blocks = ir::parse(chapter);
+					sections::prepare(&blocks, opts, cache, &hl);
+					blocks
+				})
+				.collect::<Vec<_>>()
+		});
+		for blocks in parsed {
+			self.chapter(blocks, &hl)
 		}
+		self.cache.save();
+		// rendered before the file is created, so a failed build doesn't leave an empty PDF
+		let mut out = Vec::new();
+		self.document.render(&mut out)?;
+		std::fs::write(format!("{}.pdf", self.title), out)
+			.map_err(|e| Error::new(format!("Unable to write document: {}", e), e))
 	}
 }
 
 /// Highlighting struct. Will be wrapped in an `Option` when passed to the chapter builder
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::large_enum_variant)]
 pub enum HL {
 	/// Use syntect highlighting (bundled and in Rust so faster)
-	syntect((SyntaxSet, StyleElement)),
+	syntect((SyntaxSet, Theme)),
 	/// Use highlight_.js highlighting (much slower. Called through Node.js)
 	highlight(String),
 }
@@ -238,12 +291,12 @@ impl ChapterMap {
 	}
 
 	/// Write the map to a set of nested lists
-	pub fn to_list(self, fs: u8, parent: &mut elements::OrderedList) {
+	pub fn into_list(self, fs: u8, parent: &mut elements::OrderedList) {
 		match self {
 			ChapterMap::Branch(name, children) => {
 				let mut child_list = elements::OrderedList::new();
 				for c in children {
-					c.to_list(fs, &mut child_list)
+					c.into_list(fs, &mut child_list)
 				}
 				let mut block = elements::LinearLayout::vertical();
 				block.push(elements::Paragraph::new(name).styled(Style::new().with_font_size(fs)));
@@ -0,0 +1,80 @@
+use genpdf::{
+	error::Error,
+	render::Area,
+	style::{Color, Style},
+	Context, Element, Mm, Position, RenderResult, Size,
+};
+
+use crate::build::flow::Measure;
+
+/// Width of lines drawn by genpdf in millimeters. genpdf doesn't set a line width, so this is the
+/// PDF default of 1pt
+const STROKE: f64 = 0.3528;
+
+/// Fill a rectangle in an area. The position is relative to the upper left corner of the area\
+/// genpdf can only stroke lines, so the rectangle is filled with overlapping horizontal lines
+pub fn fill_rect(area: &Area, position: Position, size: Size, colour: Color) {
+	let height: f64 = size.height.into();
+	if height <= 0.0 || size.width <= Mm::from(0) {
+		return
+	}
+	let style = Style::new().with_color(colour);
+	let last = (height - STROKE / 2.0).max(STROKE / 2.0);
+	let mut offset = STROKE / 2.0;
+	loop {
+		let y = position.y + Mm::from(offset.min(last));
+		area.draw_line(
+			vec![Position::new(position.x, y), Position::new(position.x + size.width, y)],
+			style,
+		);
+		if offset >= last {
+			break
+		}
+		offset += STROKE * 0.8;
+	}
+}
+
+/// Horizontal line across the full width of the area (used below headings)
+pub struct Rule {
+	colour: Color,
+}
+
+impl Rule {
+	/// Create a new rule
+	pub fn new(colour: Color) -> Self { Self { colour } }
+}
+
+impl Element for Rule {
+	fn render(&mut self, _context: &Context, area: Area<'_>, _style: Style) -> Result<RenderResult, Error> {
+		let width = area.size().width;
+		let y = Mm::from(STROKE / 2.0);
+		area.draw_line(
+			vec![Position::new(0, y), Position::new(width, y)],
+			Style::new().with_color(self.colour),
+		);
+		Ok(RenderResult {
+			size: Size::new(width, STROKE),
+			has_more: false,
+		})
+	}
+}
+
+impl Measure for Rule {
+	fn height(&mut self, _width: Mm, _context: &Context, _style: Style) -> Mm { Mm::from(STROKE) }
+}
+
+/// Ascent and descent of the font for a style. Both are positive distances from the baseline\
+/// Text sections place the baseline a full glyph height (ascent plus descent) of the section style
+/// below the section position, so text is drawn lower than its position by the font's descent
+pub fn font_metrics(context: &Context, style: Style) -> (Mm, Mm) {
+	let font = context.font_cache.get_rt_font(style.font(&context.font_cache));
+	let metrics = font.v_metrics_unscaled();
+	let scale = f64::from(style.font_size()) / f64::from(font.units_per_em());
+	(
+		Mm::from(pt_to_mm(f64::from(metrics.ascent) * scale)),
+		Mm::from(pt_to_mm(f64::from(-metrics.descent) * scale)),
+	)
+}
+
+/// Convert points to millimeters
+fn pt_to_mm(pt: f64) -> f64 { pt * 25.4 / 72.0 }
@@ -0,0 +1,271 @@
+use genpdf::{
+	elements::PageBreak,
+	error::{Error, ErrorKind},
+	render::Area,
+	style::Style,
+	Context, Element, Mm, Position, RenderResult, Size,
+};
+
+/// Indent of list items in mm
+const LIST_INDENT: f64 = 10.0;
+/// Space between list markers and the items in mm
+const MARKER_SPACE: f64 = 2.0;
+
+/// Element that can be measured before it's rendered, so that it can be moved to the next page if
+/// it doesn't fit
+pub trait Measure: Element {
+	/// Height of what's left of the element when rendered within the given width
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm;
+
+	/// Height of the start of the element, which has to fit for the element to be started on a
+	/// page. This is the whole element by default
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm { self.height(width, context, style) }
+}
+
+/// How an entry of a [Flow] is kept on a page
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+	/// Only the start of the entry has to fit
+	Split,
+	/// The entry is kept on the same page as the start of the next entry (used for headings)
+	WithNext,
+	/// The whole entry has to fit, unless it's taller than the flow's keep height
+	Together,
+}
+
+/// Vertical layout of blocks with page break control\
+/// Before an entry is started, the height it needs is measured, and entries that don't have the
+/// room are moved to the next page. The first entry on a new page is always rendered, so nothing
+/// is moved on forever, and rendering fails if it can't render anything there. List items are
+/// entries with a marker in their indent\
+/// The flow only has a size once something in it has been rendered, so genpdf can tell when
+/// nothing fits on a page
+pub struct Flow {
+	entries: Vec<Entry>,
+	/// Tallest entry in mm that's kept together
+	max_keep: f64,
+	style: Style,
+	/// Index of the next entry to render
+	render_idx: usize,
+	/// Set after the first call to render. Every later call is on a new page
+	continued: bool,
+}
+
+/// Element in a flow
+struct Entry {
+	element: Box<dyn Measure>,
+	/// Space above and below the element in mm
+	space: (f64, f64),
+	/// Indent in mm
+	indent: f64,
+	/// List marker, drawn before the first line of the element
+	marker: Option<String>,
+	keep: Keep,
+	/// Set once some of the element has been rendered
+	started: bool,
+}
+
+impl Flow {
+	/// Create a new empty flow. Entries kept together that are taller than `max_keep` mm can be
+	/// split
+	pub fn new(max_keep: f64) -> Self {
+		Self {
+			entries: Vec::new(),
+			max_keep,
+			style: Style::new(),
+			render_idx: 0,
+			continued: false,
+		}
+	}
+
+	/// Set the style of the flow and its list markers
+	pub fn with_style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	/// Add an element with some space above and below it
+	pub fn push(&mut self, element: impl Measure + 'static, space: (f64, f64), keep: Keep) {
+		self.entries.push(Entry {
+			element: Box::new(element),
+			space,
+			indent: 0.0,
+			marker: None,
+			keep,
+			started: false,
+		})
+	}
+
+	/// Add a list item with its marker
+	pub fn push_item(&mut self, element: impl Measure + 'static, marker: String, keep: Keep) {
+		self.entries.push(Entry {
+			element: Box::new(element),
+			space: (0.0, 0.0),
+			indent: LIST_INDENT,
+			marker: Some(marker),
+			keep,
+			started: false,
+		})
+	}
+
+	/// Height needed to start an entry on this page
+	fn needed(&mut self, i: usize, width: Mm, context: &Context, style: Style) -> Mm {
+		let max_keep = self.max_keep;
+		let entry = &mut self.entries[i];
+		let inner = width - Mm::from(entry.indent);
+		let before = if entry.started {
+			Mm::from(0)
+		} else {
+			Mm::from(entry.space.0)
+		};
+		match entry.keep {
+			Keep::WithNext => {
+				let own = before + entry.element.height(inner, context, style) + Mm::from(entry.space.1);
+				if i + 1 < self.entries.len() {
+					own + self.needed(i + 1, width, context, style)
+				} else {
+					own
+				}
+			}
+			Keep::Together => {
+				let height = entry.element.height(inner, context, style);
+				if f64::from(height) <= max_keep {
+					before + height
+				} else {
+					before + entry.element.lead(inner, context, style)
+				}
+			}
+			Keep::Split => before + entry.element.lead(inner, context, style),
+		}
+	}
+}
+
+impl Element for Flow {
+	fn render(&mut self, context: &Context, mut area: Area<'_>, style: Style) -> Result<RenderResult, Error> {
+		let style = style.and(self.style);
+		let mut result = RenderResult::default();
+		let width = area.size().width;
+		let continued = std::mem::replace(&mut self.continued, true);
+		let mut first = true;
+		while self.render_idx < self.entries.len() {
+			let forced = continued && first;
+			if !forced && !self.entries[self.render_idx].started {
+				let needed = self.needed(self.render_idx, area.size().width, context, style);
+				if needed > area.size().height {
+					break
+				}
+			}
+			let entry = &mut self.entries[self.render_idx];
+			let height = result.size.height;
+			if !entry.started {
+				area.add_offset(Position::new(0, entry.space.0));
+				result.size.height += Mm::from(entry.space.0);
+			}
+			let mut inner = area.clone();
+			inner.add_offset(Position::new(entry.indent, 0));
+			let mut entry_result = entry.element.render(context, inner.clone(), style)?;
+			let empty = |result: &RenderResult| result.has_more && result.size == Size::new(0, 0);
+			if forced && empty(&entry_result) {
+				// elements that move themselves to the next page (like nested flows) are started
+				// when they're rendered again, so they get a second try before giving up
+				entry_result = entry.element.render(context, inner, style)?;
+				if empty(&entry_result) {
+					return Err(Error::new(
+						"Unable to fit a block on a new page",
+						ErrorKind::PageSizeExceeded,
+					))
+				}
+			}
+			if empty(&entry_result) {
+				// nothing was rendered, so the space above goes to the next page with the entry
+				result.size.height = height;
+				result.has_more = true;
+				return Ok(result)
+			}
+			if entry_result.size != Size::new(0, 0) {
+				result.size.width = width
+			}
+			if entry_result.size.height > Mm::from(0) {
+				if let Some(marker) = entry.marker.take() {
+					let x = Mm::from(entry.indent - MARKER_SPACE) - style.str_width(&context.font_cache, &marker);
+					area.print_str(&context.font_cache, Position::new(x, 0), style, marker)?;
+				}
+				entry.started = true
+			}
+			area.add_offset(Position::new(0, entry_result.size.height));
+			result.size.height += entry_result.size.height;
+			first = false;
+			if entry_result.has_more {
+				result.has_more = true;
+				return Ok(result)
+			}
+			area.add_offset(Position::new(0, entry.space.1));
+			result.size.height += Mm::from(entry.space.1);
+			self.render_idx += 1
+		}
+		result.has_more = self.render_idx < self.entries.len();
+		Ok(result)
+	}
+}
+
+impl Measure for Flow {
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		let style = style.and(self.style);
+		self.entries[self.render_idx..]
+			.iter_mut()
+			.map(|entry| {
+				let before = if entry.started { 0.0 } else { entry.space.0 };
+				let height = entry.element.height(width - Mm::from(entry.indent), context, style);
+				Mm::from(before) + height + Mm::from(entry.space.1)
+			})
+			.sum()
+	}
+
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		let style = style.and(self.style);
+		if self.render_idx < self.entries.len() {
+			self.needed(self.render_idx, width, context, style)
+		} else {
+			Mm::from(0)
+		}
+	}
+}
+
+impl Measure for PageBreak {
+	fn height(&mut self, _width: Mm, _context: &Context, _style: Style) -> Mm { Mm::from(0) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::build::{testing::render, text::TextBlock};
+
+	fn para(text: &str, size: u8) -> TextBlock {
+		let mut para = TextBlock::new();
+		para.push_styled(text, Style::new().with_font_size(size));
+		para
+	}
+
+	#[test]
+	fn line_taller_than_page() {
+		// lines are fitted by the paragraph style, which the inner flow sets
+		let mut tall = Flow::new(120.0).with_style(Style::new().with_font_size(250));
+		tall.push(para("Tall", 250), (0.0, 0.0), Keep::Split);
+		let mut flow = Flow::new(120.0);
+		flow.push(para("Before", 10), (0.0, 2.0), Keep::Split);
+		flow.push(tall, (0.0, 2.0), Keep::Split);
+		assert!(render(flow, (100.0, 100.0)).is_err());
+	}
+
+	#[test]
+	fn nested_keep_taller_than_page() {
+		// the inner flow moves its first entry on, and is started when it's forced on the next page
+		let text = vec!["word"; 300].join(" ");
+		let mut inner = Flow::new(120.0);
+		inner.push(para(&text, 10), (0.0, 0.0), Keep::Together);
+		let mut flow = Flow::new(120.0);
+		flow.push(para("Before", 10), (0.0, 2.0), Keep::Split);
+		flow.push_item(inner, "1.".to_string(), Keep::Split);
+		assert!(render(flow, (100.0, 100.0)).is_ok());
+	}
+}
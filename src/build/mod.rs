@@ -1,4 +1,18 @@
+mod cache;
+mod callout;
+mod code;
 mod document;
+mod draw;
+mod flow;
+mod print;
 pub(crate) mod sections;
+mod table;
+#[cfg(test)]
+mod testing;
+mod text;
 
 pub use document::Generator;
+
+/// Smallest font size code blocks and tables are shrunk to when they don't fit the page. Lines and
+/// words that still don't fit are wrapped or split
+const MIN_SHRINK_SIZE: u8 = 4;
@@ -0,0 +1,199 @@
+use genpdf::style::Color;
+
+use crate::{
+	config::{Background, CodeBackground, Colour, Config},
+	highlight::Span,
+};
+
+/// Darkest grey used for coloured text in grayscale mode. Lighter text is darkened to this so it
+/// stays readable
+const DARKEST_INK: u8 = 100;
+
+/// Grey with the same perceived lightness as a colour (Rec. 601 luma). Used for backgrounds and
+/// lines
+pub fn grey(colour: Color) -> Color {
+	let (r, g, b) = rgb(colour);
+	Color::Greyscale((0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64).round() as u8)
+}
+
+/// Grey for coloured text. Like [grey], but never lighter than [DARKEST_INK]
+pub fn ink(colour: Color) -> Color {
+	match grey(colour) {
+		Color::Greyscale(g) => Color::Greyscale(g.min(DARKEST_INK)),
+		other => other,
+	}
+}
+
+/// Map the colours of highlighted code to a monochrome scheme\
+/// Highlighting themes give each class of token its own hue, so the hue picks the style: purple
+/// (keywords) is bold, blue (functions and titles) is bold and dark grey, green and cyan
+/// (strings) are italic, orange and yellow (numbers and types) are dark grey, and red (variables
+/// and tags) is black. Pale greys (comments) are italic and mid grey. Bold and italic from the
+/// theme are kept, and token backgrounds become grey
+pub fn code(lines: &mut [Vec<Span>]) {
+	for span in lines.iter_mut().flatten() {
+		span.background = span.background.map(grey);
+		let s = &mut span.s;
+		let Some(colour) = s.style.color() else { continue };
+		let (hue, saturation, lightness) = hsl(colour);
+		let (shade, bold, italic) = if saturation < 0.15 {
+			if lightness > 0.45 {
+				(110, false, true)
+			} else {
+				(0, false, false)
+			}
+		} else {
+			match hue {
+				h if (260.0..330.0).contains(&h) => (0, true, false),
+				h if (190.0..260.0).contains(&h) => (50, true, false),
+				h if (70.0..190.0).contains(&h) => (60, false, true),
+				h if (20.0..70.0).contains(&h) => (80, false, false),
+				_ => (0, false, false),
+			}
+		};
+		s.style.set_color(Color::Greyscale(shade));
+		if bold {
+			s.style.set_bold()
+		}
+		if italic {
+			s.style.set_italic()
+		}
+	}
+}
+
+/// Convert every colour in the config to grey. Text colours are kept dark enough to read
+pub fn config(opts: &mut Config) {
+	let fill = |c: &mut Colour| c.0 = grey(c.0);
+	let text = |c: &mut Option<Colour>| {
+		if let Some(c) = c {
+			c.0 = ink(c.0)
+		}
+	};
+	let code = &mut opts.code;
+	fill(&mut code.highlight_colour);
+	fill(&mut code.border_colour);
+	fill(&mut code.diff_added);
+	fill(&mut code.diff_removed);
+	for background in [&mut code.background, &mut code.inline_background] {
+		if let CodeBackground::Colour(c) = background {
+			fill(c)
+		}
+	}
+	let table = &mut opts.table;
+	fill(&mut table.border_colour);
+	for background in [&mut table.header_background, &mut table.stripe_background] {
+		if let Background::Colour(c) = background {
+			fill(c)
+		}
+	}
+	let style = &mut opts.style;
+	for heading in [
+		&mut style.h1,
+		&mut style.h2,
+		&mut style.h3,
+		&mut style.h4,
+		&mut style.h5,
+		&mut style.h6,
+	] {
+		text(&mut heading.colour)
+	}
+	text(&mut opts.text.colour);
+	text(&mut opts.link.colour);
+	text(&mut opts.quote.colour);
+	fill(&mut opts.quote.bar_colour);
+	// links can't be told apart by colour, so they're italic
+	opts.link.italic = true
+}
+
+/// Red, green and blue channels of a colour
+fn rgb(colour: Color) -> (u8, u8, u8) {
+	match colour {
+		Color::Rgb(r, g, b) => (r, g, b),
+		Color::Cmyk(c, m, y, k) => {
+			let channel = |x: u8| ((255 - x as u16) * (255 - k as u16) / 255) as u8;
+			(channel(c), channel(m), channel(y))
+		}
+		Color::Greyscale(g) => (g, g, g),
+	}
+}
+
+/// Hue of a colour in degrees, and its saturation and lightness from 0 to 1
+fn hsl(colour: Color) -> (f64, f64, f64) {
+	let (r, g, b) = rgb(colour);
+	let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+	let max = r.max(g).max(b);
+	let min = r.min(g).min(b);
+	let lightness = (max + min) / 2.0;
+	let delta = max - min;
+	if delta == 0.0 {
+		return (0.0, 0.0, lightness)
+	}
+	let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+	let hue = if max == r {
+		60.0 * (((g - b) / delta).rem_euclid(6.0))
+	} else if max == g {
+		60.0 * ((b - r) / delta + 2.0)
+	} else {
+		60.0 * ((r - g) / delta + 4.0)
+	};
+	(hue, saturation, lightness)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn greys() {
+		assert_eq!(grey(Color::Rgb(255, 255, 255)), Color::Greyscale(255));
+		assert_eq!(grey(Color::Rgb(255, 0, 0)), Color::Greyscale(76));
+		assert_eq!(grey(Color::Rgb(0, 255, 0)), Color::Greyscale(150));
+		assert_eq!(grey(Color::Greyscale(42)), Color::Greyscale(42));
+		assert_eq!(grey(Color::Cmyk(0, 0, 0, 255)), Color::Greyscale(0));
+		assert_eq!(grey(Color::Cmyk(0, 0, 0, 0)), Color::Greyscale(255));
+		// text is never lighter than the darkest ink
+		assert_eq!(ink(Color::Rgb(0, 255, 0)), Color::Greyscale(DARKEST_INK));
+		assert_eq!(ink(Color::Rgb(255, 0, 0)), Color::Greyscale(76));
+	}
+
+	#[test]
+	fn config_colours() {
+		let mut opts = toml::from_str::<Config>(
+			"
+			[text]
+			colour = \"#00ff00\"
+			[link]
+			colour = \"#0000ff\"
+			[quote]
+			bar-colour = \"#00ff00\"
+			[code]
+			background = \"#ff0000\"
+			inline-background = \"theme\"
+			[table]
+			header-background = \"#00ff00\"
+			[style.h1]
+			colour = \"#ff0000\"
+			",
+		)
+		.unwrap();
+		config(&mut opts);
+		// backgrounds and lines keep their lightness, and text is darkened
+		assert_eq!(opts.text.colour.map(|c| c.0), Some(Color::Greyscale(DARKEST_INK)));
+		assert_eq!(opts.link.colour.map(|c| c.0), Some(Color::Greyscale(29)));
+		assert_eq!(opts.style.h1.colour.map(|c| c.0), Some(Color::Greyscale(76)));
+		assert_eq!(opts.quote.bar_colour.0, Color::Greyscale(150));
+		assert!(matches!(
+			opts.code.background,
+			CodeBackground::Colour(Colour(Color::Greyscale(76)))
+		));
+		assert!(matches!(opts.code.inline_background, CodeBackground::Theme));
+		assert!(matches!(
+			opts.table.header_background,
+			Background::Colour(Colour(Color::Greyscale(150)))
+		));
+		assert!(matches!(opts.table.border_colour, Colour(Color::Greyscale(_))));
+		assert!(matches!(opts.code.diff_added, Colour(Color::Greyscale(_))));
+		assert_eq!(opts.quote.colour.map(|c| c.0), None);
+		assert!(opts.link.italic);
+	}
+}
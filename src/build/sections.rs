@@ -1,328 +1,464 @@
-use std::collections::HashSet;
-
-use ego_tree::{iter::Children, NodeRef};
-use genpdf::{elements, style::Style, Element};
-use pulldown_cmark::Parser;
-use scraper::{Html, Node};
+use genpdf::{
+	elements,
+	style::{Color, Style, StyledString},
+};
 
 use crate::{
-	build::{document::HL, Generator},
-	highlight,
+	build::{
+		cache::HighlightCache,
+		callout::Callout,
+		code::{expand_tabs, mark_diff, split_diff, CodeBlock, DiffLine},
+		document::HL,
+		draw::Rule,
+		flow::{Flow, Keep},
+		print,
+		table::{Cell, Table, TableDecorator, CELL_LIST_INDENT},
+		text::TextBlock,
+		Generator,
+	},
+	config::{CodeBackground, CodeOpts, PrintMode, TextAlign, DEFAULT_CODE_BACKGROUND},
+	highlight::{self, Span},
+	ir::{Alignment, Block, CodeInfo, ColumnWidth, Inline},
 };
 
-pub fn replace_reserved(s: String) -> String {
-	s.replace("&gt;", ">")
-		.replace("&lt;", "<")
-		.replace("&quot;", "\"")
-		.replace("&#39;", "'")
-		.replace("&amp;", "&")
-}
+/// Bar colour of warning callouts. The same as mdBook's light theme
+const WARNING_COLOUR: Color = Color::Rgb(255, 142, 0);
 
 impl Generator {
-	/// Generate the PDF for a book chapter
-	pub fn chapter(&mut self, chapter: &str, hl: &Option<HL>) {
-		let mut html_raw = String::new();
-		pulldown_cmark::html::push_html(&mut html_raw, Parser::new_ext(chapter, pulldown_cmark::Options::all()));
-		let fragment = Html::parse_fragment(&*html_raw);
-		let tokens = fragment.root_element();
-		let new = self.parse_children(
-			tokens.children(),
-			Style::new().with_font_size(self.pdf_opts.font_size.text),
+	/// Lower a parsed book chapter into PDF elements
+	pub fn chapter(&mut self, blocks: Vec<Block>, hl: &Option<HL>) {
+		// a heading with a page break before it already starts the chapter on a new page
+		let breaks = matches!(blocks.first(), Some(Block::Heading { level, .. }) if self.pdf_opts.style.get(*level).page_break_before);
+		let new = self.blocks(
+			blocks,
+			self.text_style().with_font_size(self.pdf_opts.font_size.text),
+			false,
 			hl,
 		);
-		if self.pdf_opts.page.new_pages {
+		if self.pdf_opts.page.new_pages && !breaks {
 			self.document.push(elements::PageBreak::new())
 		}
 		self.document.push(new);
 	}
 
-	/// Main caller function
-	fn parse_children(&mut self, children: Children<Node>, style: Style, hl: &Option<HL>) -> elements::LinearLayout {
-		let mut out = elements::LinearLayout::vertical();
-		for child in children {
-			match child.value() {
-				Node::Element(e) => match e.name() {
-					"h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
-						let mut para = elements::Paragraph::new("");
-						self.paragraph(
-							child.children(),
-							style.with_font_size(self.pdf_opts.font_size.get(e.name())),
-							&mut para,
-						);
-						out.push(para.padded((
-							if e.name() == "h1" {
-								self.pdf_opts.page.spacing.heading
-							} else {
-								0.0
-							},
-							0,
-							3,
-							0,
-						)))
+	/// Main caller function. Paragraphs in tight lists don't have space after them
+	fn blocks(&mut self, blocks: Vec<Block>, style: Style, tight: bool, hl: &Option<HL>) -> Flow {
+		let keep = &self.pdf_opts.keep;
+		let (keep_headings, keep_code, keep_tables) = (keep.headings, keep.code, keep.tables);
+		let mut out = Flow::new(keep.max_height);
+		for block in blocks {
+			match block {
+				Block::Heading { level, content, .. } => {
+					let opts = self.pdf_opts.style.get(level);
+					let (colour, rule, space_after) = (opts.colour, opts.rule_below, opts.space_after);
+					let before = opts.space_before.unwrap_or(if level == 1 {
+						self.pdf_opts.page.spacing.heading
+					} else {
+						0.0
+					});
+					if opts.page_break_before {
+						out.push(elements::PageBreak::new(), (0.0, 0.0), Keep::Split)
+					}
+					let mut heading_style = style.with_font_size(self.pdf_opts.font_size.get(&format!("h{}", level)));
+					if let Some(colour) = colour {
+						heading_style.set_color(colour.0)
+					}
+					if opts.bold {
+						heading_style.set_bold()
 					}
-					"p" => {
-						let mut para = elements::Paragraph::new("");
-						self.paragraph(child.children(), style, &mut para);
-						out.push(para.padded((0, 0, 1, 0)))
+					if opts.italic {
+						heading_style.set_italic()
 					}
-					"ol" => {
-						let mut block = elements::LinearLayout::vertical();
-						self.ordered_list(child.children(), style, &mut block);
-						out.push(block)
+					let mut para = TextBlock::new().with_alignment(match opts.align {
+						TextAlign::left => genpdf::Alignment::Left,
+						TextAlign::center => genpdf::Alignment::Center,
+						TextAlign::right => genpdf::Alignment::Right,
+					});
+					self.paragraph(content, heading_style, &mut para, hl);
+					let keep = if keep_headings { Keep::WithNext } else { Keep::Split };
+					if rule {
+						// the rule is always kept with its heading
+						out.push(para, (before, 1.0), Keep::WithNext);
+						let colour = colour.map_or(Color::Rgb(0, 0, 0), |c| c.0);
+						out.push(Rule::new(colour), (0.0, space_after), keep)
+					} else {
+						out.push(para, (before, space_after), keep)
 					}
-					"ul" => {
-						let mut block = elements::LinearLayout::vertical();
-						self.unordered_list(child.children(), style, &mut block);
-						out.push(block)
+				}
+				Block::Paragraph(content) => {
+					let para = self.text(content, style, hl);
+					out.push(para, (0.0, if tight { 0.0 } else { 1.0 }), Keep::Split)
+				}
+				Block::List { start, tight, items } => {
+					out.push(self.list(start, items, tight, style, hl), (0.0, 0.0), Keep::Split)
+				}
+				Block::Code { info, source, .. } => out.push(
+					self.code(info, source, hl),
+					(0.0, 1.0),
+					if keep_code { Keep::Together } else { Keep::Split },
+				),
+				Block::Table {
+					alignments,
+					widths,
+					head,
+					body,
+				} => out.push(
+					self.table(alignments, widths, head, body, style, hl),
+					(2.0, 2.0),
+					if keep_tables { Keep::Together } else { Keep::Split },
+				),
+				Block::Div(content) => out.push(self.blocks(content, style, tight, hl), (0.0, 0.0), Keep::Split),
+				Block::Warning(content) => out.push(
+					Callout::new(self.blocks(content, style, false, hl), self.colour(WARNING_COLOUR)),
+					(0.0, 1.0),
+					Keep::Split,
+				),
+				Block::Quote(content) => {
+					let opts = &self.pdf_opts.quote;
+					let mut quote_style = style;
+					if let Some(colour) = opts.colour {
+						quote_style.set_color(colour.0)
 					}
-					"pre" => {
-						let mut block = elements::LinearLayout::vertical();
-						self.code(child.children(), &mut block, hl);
-						out.push(block.padded((0, 0, 1, 0)))
+					if opts.italic {
+						quote_style.set_italic()
+					}
+					let bar = opts.bar_colour.0;
+					out.push(
+						Callout::new(self.blocks(content, quote_style, false, hl), bar),
+						(0.0, 1.0),
+						Keep::Split,
+					)
+				}
+				Block::Details { summary, content } => {
+					// details can't be collapsed in a PDF, so the summary is shown as a lead-in
+					if !summary.is_empty() {
+						let mut para = TextBlock::new();
+						self.paragraph(summary, style.bold(), &mut para, hl);
+						out.push(para, (0.0, 1.0), Keep::WithNext)
 					}
-					"table" => out.push(self.table(child.children(), style)),
-					_ => {}
-				},
-				_ => {}
+					out.push(self.blocks(content, style, tight, hl), (0.0, 0.0), Keep::Split)
+				}
 			}
 		}
 		out
 	}
 
-	/// Paragraph generation. Uses a [pdfgen::elements::Paragraph]
-	fn paragraph(&mut self, children: Children<Node>, style: Style, parent: &mut elements::Paragraph) {
-		for child in children {
-			match child.value() {
-				Node::Text(t) => parent.push_styled(replace_reserved(t.to_string().replace("\n", "")), style),
-				Node::Element(e) => match e.name() {
-					"p" | "a" => self.paragraph(child.children(), style, parent),
-					"strong" => self.paragraph(child.children(), style.bold(), parent),
-					"em" => self.paragraph(child.children(), style.italic(), parent),
-					"code" => self.paragraph(child.children(), style.with_font_family(self.monospace), parent),
-					_ => {}
-				},
-				_ => {}
+	/// List generation. Items in tight lists with a single paragraph are a [TextBlock], and
+	/// anything else is a nested [Flow]
+	fn list(&mut self, start: Option<u64>, items: Vec<Vec<Block>>, tight: bool, style: Style, hl: &Option<HL>) -> Flow {
+		let keep = if self.pdf_opts.keep.list_items {
+			Keep::Together
+		} else {
+			Keep::Split
+		};
+		let mut out = Flow::new(self.pdf_opts.keep.max_height).with_style(style);
+		for (i, mut item) in items.into_iter().enumerate() {
+			let marker = list_marker(start, i);
+			match item.as_mut_slice() {
+				[Block::Paragraph(content)] if tight => {
+					let para = self.text(std::mem::take(content), style, hl);
+					out.push_item(para, marker, keep)
+				}
+				_ => out.push_item(self.blocks(item, style, tight, hl), marker, keep),
 			}
 		}
+		out
 	}
 
-	/// Block generation. Uses a [pdfgen::elements::LinearLayout]
-	fn block(&mut self, children: Children<Node>, style: Style, parent: &mut elements::LinearLayout) {
-		for child in children {
-			macro_rules! para_call {
-				($t: expr) => {{
-					let mut para = elements::Paragraph::new("");
-					self.paragraph(child.children(), $t, &mut para);
-					parent.push(para)
-				}};
-			}
-			match child.value() {
-				Node::Text(t) => parent
-					.push(elements::Paragraph::new(replace_reserved(t.to_string().replace("\n", ""))).styled(style)),
-				Node::Element(e) => match e.name() {
-					"p" | "a" => para_call!(style),
-					"strong" => para_call!(style.bold()),
-					"em" => para_call!(style.italic()),
-					"code" => para_call!(style.with_font_family(self.monospace)),
-					_ => {}
-				},
-				_ => {}
-			}
-		}
+	/// Paragraph text block with the widow and orphan config
+	fn text(&mut self, content: Vec<Inline>, style: Style, hl: &Option<HL>) -> TextBlock {
+		let mut para = TextBlock::new().with_break_control(self.pdf_opts.keep.orphans, self.pdf_opts.keep.widows);
+		self.paragraph(content, style, &mut para, hl);
+		para
 	}
 
-	/// Code block generation
-	fn code(&mut self, mut children: Children<Node>, parent: &mut elements::LinearLayout, hl: &Option<HL>) {
-		let mut src = None;
-		let mut classes = HashSet::new();
-		if let Some(node) = children.next() {
-			if let Node::Element(e) = node.value() {
-				classes = e.classes.iter().map(|t| t.to_string()).collect::<HashSet<_>>()
-			};
-			if let Some(node) = node.children().next() {
-				if let Node::Text(t) = node.value() {
-					src = Some(t.to_string())
+	/// Paragraph generation. Uses a [TextBlock]
+	fn paragraph(&mut self, content: Vec<Inline>, style: Style, parent: &mut TextBlock, hl: &Option<HL>) {
+		for inline in content {
+			match inline {
+				Inline::Text(t) => parent.push_styled(t, style),
+				Inline::Strong(content) => self.paragraph(content, style.bold(), parent, hl),
+				Inline::Emphasis(content) => self.paragraph(content, style.italic(), parent, hl),
+				// links can't be followed in the PDF, so only their text is shown in the link style
+				Inline::Link { content, .. } => {
+					let opts = &self.pdf_opts.link;
+					let mut style = style;
+					if let Some(colour) = opts.colour {
+						style.set_color(colour.0)
+					}
+					if opts.bold {
+						style.set_bold()
+					}
+					if opts.italic {
+						style.set_italic()
+					}
+					self.paragraph(content, style, parent, hl)
 				}
-			}
-		}
-		parent.push(
-			if let Some(src) = src {
-				if let Some(hl) = hl {
-					match hl {
-						HL::syntect((ss, theme)) => highlight::syntect::highlight(classes, src, ss, theme),
-						HL::highlight(hl_src) => highlight::node::highlight(classes, hl_src, src),
+				// images can't be embedded yet, so only their alt text is shown
+				Inline::Image { alt: content, .. } => self.paragraph(content, style, parent, hl),
+				Inline::Styled { colour, bold, content } => {
+					let mut style = style;
+					if let Some((r, g, b)) = colour {
+						style.set_color(match self.pdf_opts.print_mode {
+							PrintMode::colour => Color::Rgb(r, g, b),
+							PrintMode::grayscale => print::ink(Color::Rgb(r, g, b)),
+						})
 					}
-				} else {
-					let mut block = elements::LinearLayout::vertical();
-					for line in src.lines() {
-						block.push(elements::Paragraph::new(line))
+					if bold {
+						style.set_bold()
 					}
-					block
+					self.paragraph(content, style, parent, hl)
 				}
-			} else {
-				elements::LinearLayout::vertical()
+				Inline::SoftBreak => parent.push_styled(" ", style),
+				Inline::HardBreak => parent.push_break(),
+				Inline::Code { code, language } => {
+					self.inline_code(code, language, style.with_font_family(self.monospace), parent, hl)
+				}
+			}
+		}
+	}
+
+	/// Inline code generation. Code is highlighted if a language is given in an attribute block
+	/// after the code (`` `let x = 1;`{.rust} ``), or by the inline language config option
+	fn inline_code(
+		&mut self, code: String, language: Option<String>, style: Style, parent: &mut TextBlock, hl: &Option<HL>,
+	) {
+		let language = language.or_else(|| self.pdf_opts.code.inline_language.clone());
+		let spans = self.highlight_code(hl, language.as_deref(), code);
+		let background = self.code_background(&self.pdf_opts.code.inline_background, hl);
+		for span in spans.into_iter().flatten() {
+			parent.push_background(span.s.s, style.and(span.s.style), span.background.or(background))
+		}
+	}
+
+	/// Highlight code, in monochrome in grayscale print mode
+	fn highlight_code(&self, hl: &Option<HL>, language: Option<&str>, src: String) -> Vec<Vec<Span>> {
+		let mut lines = highlight(&self.cache, hl, language, src);
+		if self.pdf_opts.print_mode == PrintMode::grayscale {
+			print::code(&mut lines)
+		}
+		lines
+	}
+
+	/// Apply the print mode to a colour that isn't from the config
+	fn colour(&self, colour: Color) -> Color {
+		match self.pdf_opts.print_mode {
+			PrintMode::colour => colour,
+			PrintMode::grayscale => print::grey(colour),
+		}
+	}
+
+	/// Get the background colour for code from a background config value
+	fn code_background(&self, setting: &CodeBackground, hl: &Option<HL>) -> Option<Color> {
+		let background = match (setting, hl) {
+			(CodeBackground::Theme, Some(HL::syntect((_, theme)))) => {
+				Some(highlight::syntect::theme_background(theme).unwrap_or(DEFAULT_CODE_BACKGROUND))
 			}
-			.styled(
+			(CodeBackground::Theme, _) => Some(DEFAULT_CODE_BACKGROUND),
+			(CodeBackground::None, _) => None,
+			(CodeBackground::Colour(c), _) => Some(c.0),
+		};
+		background.map(|c| self.colour(c))
+	}
+
+	/// Code block generation
+	fn code(&mut self, mut info: CodeInfo, source: String, hl: &Option<HL>) -> CodeBlock {
+		let (language, source, diff) = code_source(&info, source);
+		info.language = language;
+		let mut lines = highlight(&self.cache, hl, info.language.as_deref(), source);
+		let line_start = if info.line_numbers.unwrap_or(self.pdf_opts.code.line_numbers) {
+			Some(info.line_start.unwrap_or(1))
+		} else {
+			None
+		};
+		let background = self.code_background(&self.pdf_opts.code.background, hl);
+		let opts = &self.pdf_opts.code;
+		let line_backgrounds = match diff {
+			Some(kinds) => mark_diff(&mut lines, kinds, opts.diff_added.0, opts.diff_removed.0),
+			None => Vec::new(),
+		};
+		if self.pdf_opts.print_mode == PrintMode::grayscale {
+			print::code(&mut lines)
+		}
+		CodeBlock::new(lines)
+			.with_tab_width(opts.tab_width)
+			.with_line_numbers(line_start)
+			.with_highlight(info.highlight_lines, opts.highlight_colour.0)
+			.with_line_backgrounds(line_backgrounds)
+			.with_box(background, opts.border.then_some(opts.border_colour.0), opts.padding)
+			.with_overflow(opts.overflow)
+			.with_style(
 				Style::from(self.monospace)
 					.with_line_spacing(0.0)
 					.with_font_size(self.pdf_opts.font_size.text),
-			),
-		);
+			)
+	}
+
+	/// Table generation. Rows with missing cells are filled with empty cells
+	fn table(
+		&mut self, alignments: Vec<Alignment>, widths: Vec<ColumnWidth>, head: Vec<Vec<Vec<Block>>>,
+		body: Vec<Vec<Vec<Block>>>, style: Style, hl: &Option<HL>,
+	) -> Table {
+		let opts = &self.pdf_opts.table;
+		let columns = head.iter().chain(&body).map(Vec::len).max().unwrap_or(0);
+		let mut out = Table::new(widths)
+			.with_padding(opts.cell_padding)
+			.with_decorator(TableDecorator::new(opts, head.len()));
+		let style = match opts.font_size {
+			Some(size) => style.with_font_size(size),
+			None => style,
+		};
+		let head = head.into_iter().map(|row| (row, true));
+		for (cells, header) in head.chain(body.into_iter().map(|row| (row, false))) {
+			let row_style = if header { style.bold() } else { style };
+			let mut row = Vec::new();
+			for (i, blocks) in cells.into_iter().enumerate() {
+				let alignment = match alignments.get(i) {
+					Some(Alignment::Center) => genpdf::Alignment::Center,
+					Some(Alignment::Right) => genpdf::Alignment::Right,
+					_ => genpdf::Alignment::Left,
+				};
+				let mut cell = Cell::new(alignment);
+				self.cell(blocks, false, row_style, &mut cell, hl);
+				row.push(cell);
+			}
+			row.resize_with(columns, Cell::default);
+			if header {
+				out.push_header_row(row)
+			} else {
+				out.push_row(row)
+			}
+		}
+		out
 	}
 
-	/// Table generation
-	fn table(&mut self, children: Children<Node>, style: Style) -> elements::PaddedElement<elements::TableLayout> {
-		let mut rows = Vec::new();
-		let mut widths = Vec::new();
-		// parse the table based on an expected structure
-		for i in children {
-			if let Node::Element(e) = i.value() {
-				let row_style = if e.name() == "thead" { style.bold() } else { style };
-				for child in i.children() {
-					if let Node::Element(_) = child.value() {
-						let mut row: Vec<Box<dyn Element>> = Vec::new();
-						let mut row_widths = Vec::new();
-						for t in child.children() {
-							match t.value() {
-								Node::Text(t) => {
-									row.push(Box::new(
-										elements::Paragraph::new(replace_reserved(t.to_string()))
-											.styled(row_style)
-											.padded((1, 2)),
-									));
-									row_widths.push(t.len());
-								}
-								Node::Element(_) => {
-									if is_multiline(t.children().collect()) {
-										let mut block = elements::LinearLayout::vertical();
-										self.block(t.children(), row_style, &mut block);
-										row.push(Box::new(block.padded((1, 2))));
-									} else {
-										let mut block = elements::Paragraph::new("");
-										self.paragraph(t.children(), row_style, &mut block);
-										row.push(Box::new(block.padded((1, 2))));
-									}
-									row_widths.push(count_string_length(t.children().collect()));
-								}
-								_ => {}
-							}
+	/// Table cell generation. Blocks are added to the cell as text blocks, with list items indented
+	fn cell(&mut self, blocks: Vec<Block>, tight: bool, style: Style, out: &mut Cell, hl: &Option<HL>) {
+		let space = if tight { 0.0 } else { 1.0 };
+		for block in blocks {
+			match block {
+				Block::Paragraph(content) | Block::Heading { content, .. } => {
+					let mut para = TextBlock::new();
+					self.paragraph(content, style, &mut para, hl);
+					out.push(para, space)
+				}
+				Block::List { start, tight, items } => {
+					out.indent(CELL_LIST_INDENT);
+					for (i, item) in items.into_iter().enumerate() {
+						out.set_marker(StyledString::new(list_marker(start, i), style));
+						self.cell(item, tight, style, out, hl)
+					}
+					out.indent(-CELL_LIST_INDENT)
+				}
+				Block::Code { info, source, .. } => {
+					let (language, source, _) = code_source(&info, source);
+					let background = self.code_background(&self.pdf_opts.code.background, hl);
+					let code_style = style.with_font_family(self.monospace);
+					let mut code = TextBlock::new();
+					for (i, mut line) in self
+						.highlight_code(hl, language.as_deref(), source)
+						.into_iter()
+						.enumerate()
+					{
+						if i > 0 {
+							code.push_break()
+						}
+						expand_tabs(&mut line, self.pdf_opts.code.tab_width);
+						for span in line {
+							code.push_background(span.s.s, code_style.and(span.s.style), span.background.or(background))
 						}
-						rows.push(row);
-						widths.push(row_widths);
 					}
+					out.push_code(code, space)
 				}
-			}
-		}
-		let width = rows.iter().map(|t| t.len()).max().unwrap_or(0);
-		let widths = widths
-			.iter()
-			.map(|t| {
-				let mut out = t.clone();
-				out.extend(vec![0; width - t.len()]);
-				out
-			})
-			.fold(vec![Vec::new(); width], |mut acc, elem| {
-				for (i, t) in elem.iter().enumerate() {
-					acc[i].push(*t)
+				Block::Div(content) | Block::Warning(content) | Block::Quote(content) => {
+					self.cell(content, tight, style, out, hl)
+				}
+				Block::Details { summary, content } => {
+					let mut para = TextBlock::new();
+					self.paragraph(summary, style.bold(), &mut para, hl);
+					out.push(para, space);
+					self.cell(content, tight, style, out, hl)
 				}
-				acc
-			});
-		let mean_widths = widths
-			.iter()
-			.map(|t| t.iter().sum::<usize>() / t.len())
-			.collect::<Vec<_>>();
-		let max_width = *mean_widths.iter().max().unwrap_or(&1);
-		let mut out = elements::TableLayout::new(mean_widths.iter().map(|w| (3 * *w / max_width).max(1)).collect());
-		#[allow(unused_must_use)]
-		for mut row in rows {
-			for _ in 0..width - row.len() {
-				row.push(Box::new(elements::Paragraph::new("")))
+				// tables can't be written in table cells
+				Block::Table { .. } => {}
 			}
-			// we don't need to check for an error because we already made sure that all the rows
-			// are the same length
-			out.push_row(row);
 		}
-		out.set_cell_decorator(elements::FrameCellDecorator::new(true, false, false));
-		out.padded((2, 0))
 	}
 }
 
-/// Checks if a section is multiline or not. Used by lists and tables when determining is they
-/// should use a `LinearElement` or `Paragraph`
-fn is_multiline(children: Vec<NodeRef<Node>>) -> bool {
-	let mut out = false;
-	for i in children {
-		match i.value() {
-			Node::Text(t) => out = t.to_string().contains("\n"),
-			Node::Element(_) => out = is_multiline(i.children().collect()),
-			_ => {}
+/// Highlight all the code in some parsed blocks so it's in the cache before the blocks are turned
+/// into PDF elements. This is the slow part of building, so it's done for chapters in parallel
+pub fn prepare(blocks: &[Block], opts: &CodeOpts, cache: &HighlightCache, hl: &Option<HL>) {
+	fn inlines(content: &[Inline], opts: &CodeOpts, cache: &HighlightCache, hl: &Option<HL>) {
+		for inline in content {
+			match inline {
+				Inline::Text(_) | Inline::SoftBreak | Inline::HardBreak => {}
+				Inline::Strong(content)
+				| Inline::Emphasis(content)
+				| Inline::Link { content, .. }
+				| Inline::Image { alt: content, .. }
+				| Inline::Styled { content, .. } => inlines(content, opts, cache, hl),
+				Inline::Code { code, language } => {
+					let language = language.as_ref().or(opts.inline_language.as_ref());
+					highlight(cache, hl, language.map(|l| l.as_str()), code.clone());
+				}
+			}
 		}
-		if out {
-			break
+	}
+	for block in blocks {
+		match block {
+			Block::Heading { content, .. } | Block::Paragraph(content) => inlines(content, opts, cache, hl),
+			Block::Div(content) | Block::Warning(content) | Block::Quote(content) => prepare(content, opts, cache, hl),
+			Block::Details { summary, content } => {
+				inlines(summary, opts, cache, hl);
+				prepare(content, opts, cache, hl)
+			}
+			Block::List { items, .. } => {
+				for item in items {
+					prepare(item, opts, cache, hl)
+				}
+			}
+			Block::Code { info, source, .. } => {
+				let (language, source, _) = code_source(info, source.clone());
+				highlight(cache, hl, language.as_deref(), source);
+			}
+			Block::Table { head, body, .. } => {
+				for cell in head.iter().chain(body).flatten() {
+					prepare(cell, opts, cache, hl)
+				}
+			}
 		}
 	}
-	out
 }
 
-/// Count the cumulative length of the strings contained under an element
-fn count_string_length(children: Vec<NodeRef<Node>>) -> usize {
-	let mut out = 0;
-	for child in children {
-		match child.value() {
-			Node::Text(t) => out += t.len(),
-			Node::Element(_) => out += count_string_length(child.children().collect()),
-			_ => {}
-		}
+/// Marker of the list item at an index. Ordered lists count up from their start, and unordered
+/// lists use a dash
+fn list_marker(start: Option<u64>, i: usize) -> String {
+	match start {
+		Some(start) => format!("{}.", start + i as u64),
+		None => "\u{2013}".to_string(),
 	}
-	out
 }
 
-/// List macro. Both lists are almost identical, so we use a macro to generate the functions to
-/// ensure concurrency between the functions
-macro_rules! list {
-	($name: ident, $t: ty) => {
-		impl Generator {
-			fn $name(&mut self, children: Children<Node>, style: Style, parent: &mut elements::LinearLayout) {
-				let mut out = <$t>::new();
-				if is_multiline(
-					children
-						.clone()
-						.filter_map(|t| {
-							if let Node::Element(_) = t.value() {
-								Some(t)
-							} else {
-								None
-							}
-						})
-						.collect(),
-				) {
-					for child in children {
-						match child.value() {
-							Node::Element(_) => {
-								let mut block = elements::LinearLayout::vertical();
-								self.block(child.children(), style, &mut block);
-								out.push(block)
-							}
-							_ => {}
-						}
-					}
-				} else {
-					for child in children {
-						match child.value() {
-							Node::Element(_) => {
-								let mut block = elements::Paragraph::new("");
-								self.paragraph(child.children(), style, &mut block);
-								out.push(block)
-							}
-							_ => {}
-						}
-					}
-				}
-				parent.push(out.styled(style))
-			}
-		}
-	};
+/// Highlight code as lines of styled strings. Highlighted code is cached between builds
+fn highlight(cache: &HighlightCache, hl: &Option<HL>, language: Option<&str>, src: String) -> Vec<Vec<Span>> {
+	match (hl, language) {
+		(Some(hl), Some(language)) => cache.get_or_insert_with(language, &src, || match hl {
+			HL::syntect((ss, theme)) => highlight::syntect::highlight(language, src.clone(), ss, theme),
+			HL::highlight(hl_src) => highlight::node::highlight(language, hl_src, src.clone()),
+		}),
+		_ => highlight::util::plain_lines(&src),
+	}
 }
 
-list!(unordered_list, elements::UnorderedList);
-list!(ordered_list, elements::OrderedList);
+/// Get the language and source to highlight for a code block. Diff blocks with a language
+/// (`diff-rust`) are highlighted as that language, with the diff markers put back afterwards
+fn code_source(info: &CodeInfo, source: String) -> (Option<String>, String, Option<Vec<DiffLine>>) {
+	match info.language.as_deref().and_then(|l| l.strip_prefix("diff-")) {
+		Some(language) => {
+			let (source, kinds) = split_diff(&source);
+			(Some(language.to_string()), source, Some(kinds))
+		}
+		None => (info.language.clone(), source, None),
+	}
+}
@@ -0,0 +1,621 @@
+use genpdf::{
+	error::{Error, ErrorKind},
+	render::Area,
+	style::{Color, Style, StyledString},
+	Alignment, Context, Element, Mm, Position, RenderResult,
+};
+
+use crate::{
+	build::{draw::fill_rect, flow::Measure, text::TextBlock, MIN_SHRINK_SIZE},
+	config::{Borders, TableOpts},
+	ir::ColumnWidth,
+};
+
+/// Indent of list items in table cells in mm. The list markers go in the indent
+pub const CELL_LIST_INDENT: f64 = 5.0;
+
+/// Table with columns sized from their contents using the font metrics\
+/// Columns get at least their min-content width (the longest word) if they can, and the space left
+/// is shared out by how much wider their max-content width (the unwrapped text) is, like browsers
+/// lay out tables. Columns can be given fixed widths instead. genpdf can't rotate content or give
+/// pages different sizes, so a table too wide for the page has its font shrunk instead\
+/// Rows are kept together on one page unless they're taller than a page, and the header rows are
+/// drawn again at the top of each page the table continues onto, if there's room for them and the
+/// start of the next row
+pub struct Table {
+	/// Cells of each row, starting with the header rows. All rows have the same number of cells
+	rows: Vec<Vec<Cell>>,
+	/// Number of header rows
+	header_rows: usize,
+	/// Unrendered copies of the header rows, drawn on continuation pages
+	header: Vec<Vec<Cell>>,
+	/// Set after the first call to render. Every later call is on a new page
+	continued: bool,
+	/// Column widths given for the table
+	columns: Vec<ColumnWidth>,
+	grid: Grid,
+	/// Index of the next row to render
+	render_idx: usize,
+}
+
+/// Column widths, cell padding, and decorator of a table
+struct Grid {
+	/// Solved column widths. Filled on the first call to render
+	widths: Vec<Mm>,
+	/// Space between the cell borders and their contents in mm, vertically and horizontally
+	padding: (f64, f64),
+	decorator: Option<TableDecorator>,
+}
+
+impl Table {
+	/// Create a new empty table with the given column widths. Missing widths are `auto`
+	pub fn new(columns: Vec<ColumnWidth>) -> Self {
+		Self {
+			rows: Vec::new(),
+			header_rows: 0,
+			header: Vec::new(),
+			continued: false,
+			columns,
+			grid: Grid {
+				widths: Vec::new(),
+				padding: (1.0, 2.0),
+				decorator: None,
+			},
+			render_idx: 0,
+		}
+	}
+
+	/// Set the cell padding in mm, vertically and horizontally
+	pub fn with_padding(mut self, padding: (f64, f64)) -> Self {
+		self.grid.padding = padding;
+		self
+	}
+
+	/// Set the decorator used to draw cell backgrounds and borders
+	pub fn with_decorator(mut self, decorator: TableDecorator) -> Self {
+		self.grid.decorator = Some(decorator);
+		self
+	}
+
+	/// Add a header row. Header rows have to be added before the other rows
+	pub fn push_header_row(&mut self, row: Vec<Cell>) {
+		self.rows.push(row);
+		self.header_rows += 1
+	}
+
+	/// Add a row of cells
+	pub fn push_row(&mut self, row: Vec<Cell>) { self.rows.push(row) }
+
+	/// Min-content and max-content width of each column, including the cell padding
+	fn content_widths(&self, context: &Context, style: Style) -> (Vec<f64>, Vec<f64>) {
+		let count = self.rows.first().map_or(0, Vec::len);
+		let padding = self.grid.padding.1 * 2.0;
+		let mut min = vec![padding; count];
+		let mut max = vec![padding; count];
+		for row in &self.rows {
+			for (i, cell) in row.iter().enumerate() {
+				min[i] = min[i].max(f64::from(cell.min_width(context, style)) + padding);
+				max[i] = max[i].max(f64::from(cell.max_width(context, style)) + padding);
+			}
+		}
+		(min, max)
+	}
+
+	/// Work out the column widths for a table width
+	fn solve(&mut self, width: Mm, context: &Context, style: Style) {
+		let width = f64::from(width);
+		let count = self.rows.first().map_or(0, Vec::len);
+		let mut fixed = (0..count)
+			.map(|i| match self.columns.get(i) {
+				Some(ColumnWidth::Percent(percent)) => Some(width * percent / 100.0),
+				Some(ColumnWidth::Mm(mm)) => Some(*mm),
+				Some(ColumnWidth::Auto) | None => None,
+			})
+			.collect::<Vec<_>>();
+		let autos = fixed.iter().filter(|w| w.is_none()).count();
+		let auto_total = |fixed: &[Option<f64>], widths: &[f64]| {
+			(0..count)
+				.filter(|i| fixed[*i].is_none())
+				.map(|i| widths[i])
+				.sum::<f64>()
+		};
+
+		let (mut min, mut max) = self.content_widths(context, style);
+		let fixed_total = fixed.iter().flatten().sum::<f64>();
+		if fixed_total > width {
+			// fixed widths wider than the table are scaled down, leaving the auto columns their
+			// longest words, up to an equal share of the table
+			let reserve = auto_total(&fixed, &min).min(width * autos as f64 / count as f64);
+			let scale = (width - reserve) / fixed_total;
+			for w in fixed.iter_mut().flatten() {
+				*w *= scale
+			}
+		}
+		let available = (width - fixed.iter().flatten().sum::<f64>()).max(0.0);
+		let auto_total = |widths: &[f64]| auto_total(&fixed, widths);
+		let padding = self.grid.padding.1 * 2.0 * autos as f64;
+		if auto_total(&min) > available && auto_total(&min) > padding {
+			// the words don't fit, so the text is made smaller to fit the longest words
+			let scale = ((available - padding) / (auto_total(&min) - padding)).max(0.0);
+			for cell in self.rows.iter_mut().flatten() {
+				cell.scale_font_size(scale, MIN_SHRINK_SIZE)
+			}
+			(min, max) = self.content_widths(context, style);
+		}
+		let (min_total, max_total) = (auto_total(&min), auto_total(&max));
+		// share of the space for each auto column when the columns have no width to go by
+		let equal = available / autos.max(1) as f64;
+
+		self.grid.widths = (0..count)
+			.map(|i| {
+				let width = fixed[i].unwrap_or_else(|| {
+					if max_total <= 0.0 {
+						// empty columns without padding have no width to share the space by
+						equal
+					} else if max_total <= available {
+						// everything fits unwrapped, so the extra space goes to the widest columns
+						max[i] + (available - max_total) * max[i] / max_total
+					} else if min_total <= available {
+						min[i] + (max[i] - min[i]) * (available - min_total) / (max_total - min_total)
+					} else if min_total <= 0.0 {
+						equal
+					} else {
+						// even the shrunk words don't fit, so they're split between characters
+						min[i] * available / min_total
+					}
+				});
+				Mm::from(width)
+			})
+			.collect();
+		self.header = self.rows[..self.header_rows].to_vec()
+	}
+}
+
+impl Grid {
+	/// Height of what's left of a row, including the cell padding
+	fn row_height(&self, row: &mut [Cell], context: &Context, style: Style) -> Mm {
+		let padding = Mm::from(self.padding.1 * 2.0);
+		row.iter_mut()
+			.zip(&self.widths)
+			.map(|(cell, width)| cell.height(*width - padding, context, style))
+			.fold(Mm::from(0), Mm::max)
+			+ Mm::from(self.padding.0 * 2.0)
+	}
+
+	/// Height of the start of what's left of a row (the first lines of each cell), including the cell
+	/// padding. This is the least of the row that has to fit on a page
+	fn row_lead(&self, row: &mut [Cell], context: &Context, style: Style) -> Mm {
+		let padding = Mm::from(self.padding.1 * 2.0);
+		row.iter_mut()
+			.zip(&self.widths)
+			.map(|(cell, width)| cell.lead(*width - padding, context, style))
+			.fold(Mm::from(0), Mm::max)
+			+ Mm::from(self.padding.0 * 2.0)
+	}
+
+	/// Render a row. Cell backgrounds are filled to the given height first. `top` is set for the
+	/// first row on a page
+	#[allow(clippy::too_many_arguments)]
+	fn render_row(
+		&mut self, row: &mut [Cell], index: usize, top: bool, height: Mm, context: &Context, area: Area<'_>,
+		style: Style,
+	) -> Result<RenderResult, Error> {
+		let mut result = RenderResult::default();
+		let mut areas = Vec::new();
+		let mut x = Mm::from(0);
+		for width in &self.widths {
+			let mut cell_area = area.clone();
+			cell_area.add_offset(Position::new(x, 0));
+			cell_area.set_width(*width);
+			areas.push(cell_area);
+			x += *width
+		}
+		if let Some(decorator) = &mut self.decorator {
+			for area in &areas {
+				let mut area = area.clone();
+				area.set_height(height);
+				decorator.fill_cell(index, &area)
+			}
+		}
+		for (cell, area) in row.iter_mut().zip(&areas) {
+			let mut inner = area.clone();
+			inner.add_margins(self.padding);
+			let cell_result = cell.render(context, inner, style)?;
+			result.has_more |= cell_result.has_more;
+			result.size.height = result
+				.size
+				.height
+				.max(cell_result.size.height + Mm::from(self.padding.0 * 2.0));
+		}
+		if let Some(decorator) = &mut self.decorator {
+			for (i, mut area) in areas.into_iter().enumerate() {
+				area.set_height(result.size.height);
+				decorator.decorate_cell(i, top, area)
+			}
+		}
+		Ok(result)
+	}
+}
+
+impl Element for Table {
+	fn render(&mut self, context: &Context, mut area: Area<'_>, style: Style) -> Result<RenderResult, Error> {
+		let mut result = RenderResult::default();
+		if self.render_idx == 0 && self.grid.widths.is_empty() {
+			self.solve(area.size().width, context, style)
+		}
+		if self.grid.widths.is_empty() {
+			return Ok(result)
+		}
+		let table_area = area.clone();
+		result.size.width = area.size().width;
+		let continued = std::mem::replace(&mut self.continued, true);
+		// the first row rendered on each page only has the outer border above it
+		let mut top = true;
+		if continued && self.render_idx >= self.header_rows && self.render_idx < self.rows.len() {
+			let mut header = self.header.clone();
+			let heights = header
+				.iter_mut()
+				.map(|row| self.grid.row_height(row, context, style))
+				.collect::<Vec<_>>();
+			let lead = self.grid.row_lead(&mut self.rows[self.render_idx], context, style);
+			// the header isn't repeated if it would leave no room for the next row
+			if heights.iter().copied().sum::<Mm>() + lead <= area.size().height {
+				for (i, (row, height)) in header.iter_mut().zip(heights).enumerate() {
+					let row = self
+						.grid
+						.render_row(row, i, top, height, context, area.clone(), style)?;
+					result.size.height += row.size.height;
+					area.add_offset(Position::new(0, row.size.height));
+					top = false;
+				}
+			}
+		}
+		let mut first = true;
+		while self.render_idx < self.rows.len() {
+			let height = self.grid.row_height(&mut self.rows[self.render_idx], context, style);
+			// rows that don't fit are moved to the next page, except for the first row on a new
+			// page, which is split if it's taller than the page. The header rows are kept with the
+			// first row after them
+			if continued && first {
+				// not even the first line of each cell fits on a new page, so the row could never be
+				// rendered
+				if self.grid.row_lead(&mut self.rows[self.render_idx], context, style) > area.size().height {
+					return Err(Error::new(
+						format!("Table row {} is too tall for the page", self.render_idx + 1),
+						ErrorKind::PageSizeExceeded,
+					))
+				}
+			} else {
+				let end = (self.render_idx.max(self.header_rows) + 1).min(self.rows.len());
+				let together = (self.render_idx + 1..end)
+					.map(|i| self.grid.row_height(&mut self.rows[i], context, style))
+					.sum::<Mm>();
+				if height + together > area.size().height {
+					break
+				}
+			}
+			let height = if height > area.size().height {
+				area.size().height
+			} else {
+				height
+			};
+			let row = self.grid.render_row(
+				&mut self.rows[self.render_idx],
+				self.render_idx,
+				top,
+				height,
+				context,
+				area.clone(),
+				style,
+			)?;
+			result.size.height += row.size.height;
+			area.add_offset(Position::new(0, row.size.height));
+			first = false;
+			top = false;
+			if row.has_more {
+				break
+			}
+			self.render_idx += 1
+		}
+		if let Some(decorator) = &self.grid.decorator {
+			decorator.draw_frame(&table_area, result.size.height)
+		}
+		result.has_more = self.render_idx < self.rows.len();
+		Ok(result)
+	}
+}
+
+impl Measure for Table {
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		if self.render_idx == 0 && self.grid.widths.is_empty() {
+			self.solve(width, context, style)
+		}
+		let header = if self.continued && self.render_idx >= self.header_rows {
+			let grid = &self.grid;
+			self.header
+				.iter_mut()
+				.map(|row| grid.row_height(row, context, style))
+				.sum()
+		} else {
+			Mm::from(0)
+		};
+		let grid = &self.grid;
+		header
+			+ self.rows[self.render_idx..]
+				.iter_mut()
+				.map(|row| grid.row_height(row, context, style))
+				.sum::<Mm>()
+	}
+
+	/// The header rows and the first body row
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		if self.render_idx == 0 && self.grid.widths.is_empty() {
+			self.solve(width, context, style)
+		}
+		let end = (self.render_idx.max(self.header_rows) + 1).min(self.rows.len());
+		let grid = &self.grid;
+		self.rows[self.render_idx..end]
+			.iter_mut()
+			.map(|row| grid.row_height(row, context, style))
+			.sum()
+	}
+}
+
+/// Cell decorator for the table config. Cell backgrounds are filled before the cells are rendered
+/// so they don't cover the text, and the outer border is drawn around the part of the table on each
+/// page
+pub struct TableDecorator {
+	header_background: Option<Color>,
+	/// Background of every other body row
+	stripe_background: Option<Color>,
+	inner: Borders,
+	outer: bool,
+	colour: Color,
+	header_rows: usize,
+}
+
+impl TableDecorator {
+	/// Create a new decorator for a table with some header rows
+	pub fn new(opts: &TableOpts, header_rows: usize) -> Self {
+		Self {
+			header_background: opts.header_background.colour(),
+			stripe_background: opts.stripe_background.colour(),
+			inner: opts.inner_borders,
+			outer: opts.outer_border,
+			colour: opts.border_colour.0,
+			header_rows,
+		}
+	}
+
+	/// Fill the background of a cell in a row
+	fn fill_cell(&self, row: usize, area: &Area) {
+		let background = match row.checked_sub(self.header_rows) {
+			None => self.header_background,
+			Some(body_row) if body_row % 2 == 1 => self.stripe_background,
+			Some(_) => None,
+		};
+		if let Some(background) = background {
+			fill_rect(area, Position::default(), area.size(), background)
+		}
+	}
+
+	/// Draw the outer border around the part of a table on a page
+	fn draw_frame(&self, area: &Area, height: Mm) {
+		if !self.outer || height <= Mm::from(0) {
+			return
+		}
+		let width = area.size().width;
+		area.draw_line(
+			vec![
+				Position::default(),
+				Position::new(width, 0),
+				Position::new(width, height),
+				Position::new(0, height),
+				Position::default(),
+			],
+			Style::new().with_color(self.colour),
+		)
+	}
+
+	/// Draw the inner borders of a cell. Rows at the top of a page (including repeated headers)
+	/// only have the outer border above them
+	fn decorate_cell(&self, column: usize, top: bool, area: Area<'_>) {
+		let size = area.size();
+		let style = Style::new().with_color(self.colour);
+		if column > 0 && matches!(self.inner, Borders::all | Borders::columns) {
+			area.draw_line(vec![Position::default(), Position::new(0, size.height)], style)
+		}
+		if !top && matches!(self.inner, Borders::all | Borders::rows) {
+			area.draw_line(vec![Position::default(), Position::new(size.width, 0)], style)
+		}
+	}
+}
+
+/// Contents of a table cell. Paragraphs, list items, and code in a cell are laid out as text blocks
+/// under each other, which can be measured before they're rendered so rows can be kept together
+#[derive(Default, Clone)]
+pub struct Cell {
+	parts: Vec<Part>,
+	/// Alignment of paragraphs
+	alignment: Alignment,
+	/// Indent of the next part in mm
+	indent: f64,
+	/// List marker for the next part
+	marker: Option<StyledString>,
+	/// Index of the next part to render
+	render_idx: usize,
+}
+
+/// Text block in a cell
+#[derive(Clone)]
+struct Part {
+	block: TextBlock,
+	/// Space above the part in mm
+	space: f64,
+	indent: f64,
+	marker: Option<StyledString>,
+	/// Set once some of the part has been rendered
+	started: bool,
+}
+
+impl Cell {
+	/// Create a new empty cell with the alignment of its column
+	pub fn new(alignment: Alignment) -> Self {
+		Self {
+			alignment,
+			..Default::default()
+		}
+	}
+
+	/// Add a paragraph with some space above it, unless it's the first part in the cell
+	pub fn push(&mut self, block: TextBlock, space: f64) {
+		let block = block.with_alignment(self.alignment);
+		self.push_code(block, space)
+	}
+
+	/// Add code with some space above it. Code is always aligned left
+	pub fn push_code(&mut self, block: TextBlock, space: f64) {
+		self.parts.push(Part {
+			block,
+			space: if self.parts.is_empty() { 0.0 } else { space },
+			indent: self.indent,
+			marker: self.marker.take(),
+			started: false,
+		})
+	}
+
+	/// Change the indent of the parts added after this
+	pub fn indent(&mut self, by: f64) { self.indent += by }
+
+	/// Set the list marker of the next part
+	pub fn set_marker(&mut self, marker: StyledString) { self.marker = Some(marker) }
+
+	/// Width of the widest word, including indents
+	fn min_width(&self, context: &Context, style: Style) -> Mm {
+		self.parts
+			.iter()
+			.map(|part| part.block.min_width(context, style) + Mm::from(part.indent))
+			.fold(Mm::from(0), Mm::max)
+	}
+
+	/// Width of the widest unwrapped line, including indents
+	fn max_width(&self, context: &Context, style: Style) -> Mm {
+		self.parts
+			.iter()
+			.map(|part| part.block.max_width(context, style) + Mm::from(part.indent))
+			.fold(Mm::from(0), Mm::max)
+	}
+
+	/// Height of the parts left to render when laid out within the given width
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		self.parts[self.render_idx..]
+			.iter_mut()
+			.map(|part| {
+				let space = if part.started { 0.0 } else { part.space };
+				part.block.height(width - Mm::from(part.indent), context, style) + Mm::from(space)
+			})
+			.sum()
+	}
+
+	/// Height of the start of the next part, including the space above it
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		match self.parts.get_mut(self.render_idx) {
+			Some(part) => {
+				let space = if part.started { 0.0 } else { part.space };
+				part.block.lead(width - Mm::from(part.indent), context, style) + Mm::from(space)
+			}
+			None => Mm::from(0),
+		}
+	}
+
+	/// Scale the font size of all the text, down to a minimum size
+	fn scale_font_size(&mut self, scale: f64, min: u8) {
+		for part in &mut self.parts {
+			part.block.scale_font_size(scale, min);
+			if let Some(marker) = &mut part.marker {
+				let size = ((marker.style.font_size() as f64 * scale).floor() as u8).max(min);
+				marker.style.set_font_size(size)
+			}
+		}
+	}
+}
+
+impl Element for Cell {
+	fn render(&mut self, context: &Context, area: Area<'_>, style: Style) -> Result<RenderResult, Error> {
+		let mut result = RenderResult::default();
+		let mut y = Mm::from(0);
+		while let Some(part) = self.parts.get_mut(self.render_idx) {
+			if !part.started {
+				y += Mm::from(part.space)
+			}
+			let mut part_area = area.clone();
+			part_area.add_offset(Position::new(part.indent, y));
+			let part_result = part.block.render(context, part_area, style)?;
+			if part_result.size.height > Mm::from(0) {
+				// the marker is drawn next to the first line of the part
+				if let Some(marker) = part.marker.take() {
+					let position = Position::new(part.indent - CELL_LIST_INDENT, y);
+					if let Some(mut section) = area.text_section(&context.font_cache, position, style) {
+						section.print_str(&marker.s, style.and(marker.style))?;
+					}
+				}
+				part.started = true
+			}
+			y += part_result.size.height;
+			if part_result.has_more {
+				result.has_more = true;
+				break
+			}
+			self.render_idx += 1
+		}
+		result.size.width = area.size().width;
+		result.size.height = y;
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::build::testing::render;
+
+	fn cell(text: &str) -> Cell {
+		let mut block = TextBlock::new();
+		block.push_styled(text, Style::new().with_font_size(5));
+		let mut cell = Cell::new(Alignment::Left);
+		cell.push(block, 1.0);
+		cell
+	}
+
+	fn table(columns: usize, header: &str, rows: usize) -> Table {
+		let mut table = Table::new(vec![ColumnWidth::Auto; columns])
+			.with_padding((1.0, 2.0))
+			.with_decorator(TableDecorator::new(&TableOpts::default(), 1));
+		table.push_header_row((0..columns).map(|_| cell(header)).collect());
+		for i in 0..rows {
+			table.push_row((0..columns).map(|_| cell(&format!("row {}", i))).collect())
+		}
+		table
+	}
+
+	#[test]
+	fn tall_header() {
+		// the header is taller than the page, so it can't be repeated on the pages after it
+		let header = vec!["word"; 400].join(" ");
+		assert!(render(table(1, &header, 60), (210.0, 297.0)).is_ok());
+	}
+
+	#[test]
+	fn small_page() {
+		// the header leaves no room for the rows on the pages after it
+		assert!(render(table(4, "a header", 2), (60.0, 80.0)).is_ok());
+	}
+
+	#[test]
+	fn row_taller_than_page() {
+		// the cell padding alone is taller than the page, so the rows can never fit. This fails
+		// instead of adding empty pages forever
+		let table = table(1, "header", 1).with_padding((25.0, 2.0));
+		assert!(render(table, (60.0, 80.0)).is_err());
+	}
+}
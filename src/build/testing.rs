@@ -0,0 +1,30 @@
+//! Helpers for tests that render elements
+
+use genpdf::{
+	error::Error,
+	fonts::{FontData, FontFamily},
+	Document, Element, SimplePageDecorator, Size,
+};
+
+const OPEN_SANS: &[u8] = include_bytes!("../../theme/open-sans-v17-all-charsets-regular.ttf");
+const OPEN_SANS_BOLD: &[u8] = include_bytes!("../../theme/open-sans-v17-all-charsets-700.ttf");
+
+/// Render an element on its own in a document with the given page size in mm, with the default
+/// 20mm margins. Returns the size of the PDF
+pub fn render(element: impl Element + 'static, page: (f64, f64)) -> Result<usize, Error> {
+	let font = |data: &[u8]| FontData::new(data.to_vec(), None).unwrap();
+	let mut doc = Document::new(FontFamily {
+		regular: font(OPEN_SANS),
+		bold: font(OPEN_SANS_BOLD),
+		italic: font(OPEN_SANS),
+		bold_italic: font(OPEN_SANS_BOLD),
+	});
+	doc.set_paper_size(Size::new(page.0, page.1));
+	let mut decorator = SimplePageDecorator::new();
+	decorator.set_margins(20);
+	doc.set_page_decorator(decorator);
+	doc.push(element);
+	let mut out = Vec::new();
+	doc.render(&mut out)?;
+	Ok(out.len())
+}
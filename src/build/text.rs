@@ -0,0 +1,322 @@
+use genpdf::{
+	error::Error,
+	render::Area,
+	style::{Color, Style, StyledString},
+	Alignment, Context, Element, Mm, Position, RenderResult, Size,
+};
+
+use crate::{
+	build::{
+		draw::{fill_rect, font_metrics},
+		flow::Measure,
+	},
+	highlight::Span,
+};
+
+/// Paragraph element with support for span backgrounds (used for inline code)\
+/// Words are wrapped the same way as genpdf's paragraph, but lines are laid out by the element
+/// itself so that backgrounds can be drawn before the text. Words that are too long for a line
+/// are split between characters instead of being dropped\
+/// Paragraphs split over pages can be given a minimum number of lines to leave at the bottom of
+/// a page (orphans) and to carry onto the next (widows)
+#[derive(Default, Clone)]
+pub struct TextBlock {
+	/// Text spans
+	spans: Vec<Span>,
+	/// Laid out lines. Filled on the first call to render
+	lines: Vec<Vec<Span>>,
+	/// Index of the next line to render
+	render_idx: usize,
+	/// Horizontal alignment of lines
+	alignment: Alignment,
+	/// Fewest lines left at the bottom of a page when the block is split
+	orphans: usize,
+	/// Fewest lines carried onto the next page when the block is split
+	widows: usize,
+	/// Set when the block was moved to the next page to keep enough lines together. The block is
+	/// then started whatever fits, so it isn't moved on forever
+	deferred: bool,
+}
+
+impl TextBlock {
+	/// Create a new empty text block
+	pub fn new() -> Self { Self::default() }
+
+	/// Add a styled string
+	pub fn push_styled(&mut self, s: impl Into<String>, style: Style) {
+		self.spans.push(Span {
+			s: StyledString::new(s, style),
+			background: None,
+		})
+	}
+
+	/// Set the horizontal alignment of lines
+	pub fn with_alignment(mut self, alignment: Alignment) -> Self {
+		self.alignment = alignment;
+		self
+	}
+
+	/// Set the fewest lines to leave at the bottom of a page, and to carry onto the next page, when
+	/// the block is split
+	pub fn with_break_control(mut self, orphans: usize, widows: usize) -> Self {
+		self.orphans = orphans;
+		self.widows = widows;
+		self
+	}
+
+	/// Add a line break
+	pub fn push_break(&mut self) { self.push_styled("\n", Style::new()) }
+
+	/// Add a styled string with a background colour
+	pub fn push_background(&mut self, s: impl Into<String>, style: Style, background: Option<Color>) {
+		self.spans.push(Span {
+			s: StyledString::new(s, style),
+			background,
+		})
+	}
+
+	/// Width of the widest word, which is the narrowest the block can be without splitting words
+	pub fn min_width(&self, context: &Context, style: Style) -> Mm {
+		words(&self.spans)
+			.iter()
+			.map(|word| {
+				style
+					.and(word.s.style)
+					.str_width(&context.font_cache, word.s.s.trim_end())
+			})
+			.fold(Mm::from(0), Mm::max)
+	}
+
+	/// Width of the widest line if the text isn't wrapped
+	pub fn max_width(&self, context: &Context, style: Style) -> Mm {
+		let mut widest = Mm::from(0);
+		let mut x = Mm::from(0);
+		for word in words(&self.spans) {
+			if word.s.s == "\n" {
+				x = Mm::from(0);
+				continue
+			}
+			let word_style = style.and(word.s.style);
+			widest = widest.max(x + word_style.str_width(&context.font_cache, word.s.s.trim_end()));
+			x += word_style.str_width(&context.font_cache, &word.s.s)
+		}
+		widest
+	}
+
+	/// Scale the font size of all the spans, down to a minimum size
+	pub fn scale_font_size(&mut self, scale: f64, min: u8) {
+		for span in &mut self.spans {
+			let size = ((span.s.style.font_size() as f64 * scale).floor() as u8).max(min);
+			span.s.style.set_font_size(size)
+		}
+	}
+
+	/// Index of the line after the last one to render in an area of some height
+	fn fitting_lines(&mut self, height: Mm, context: &Context, style: Style) -> usize {
+		// same fit check as genpdf's text sections so that page breaks match
+		let glyph_height = style.font(&context.font_cache).glyph_height(style.font_size());
+		let mut end = self.render_idx;
+		let mut y = Mm::from(0);
+		while end < self.lines.len() && y + glyph_height <= height {
+			y += line_height(&self.lines[end], context, style);
+			end += 1
+		}
+		if end == self.lines.len() {
+			return end
+		}
+		let (fit, left) = (end - self.render_idx, self.lines.len() - self.render_idx);
+		let mut count = fit;
+		if left - fit < self.widows && left > self.widows {
+			count = left - self.widows
+		}
+		if self.render_idx == 0 && count < self.orphans && !self.deferred {
+			count = 0
+		}
+		// nothing is moved on twice, so the next page takes as many lines as fit
+		if count == 0 && self.deferred {
+			count = fit
+		}
+		self.deferred = count == 0;
+		self.render_idx + count
+	}
+
+	/// Split the spans into lines that fit within the given width
+	fn layout(&mut self, width: Mm, context: &Context, style: Style) {
+		let mut line: Vec<Span> = Vec::new();
+		let mut x = Mm::from(0);
+		for word in words(&self.spans) {
+			if word.s.s == "\n" {
+				self.lines.push(std::mem::take(&mut line));
+				x = Mm::from(0);
+				continue
+			}
+			let word_style = style.and(word.s.style);
+			let trimmed = word_style.str_width(&context.font_cache, word.s.s.trim_end());
+			if x > Mm::from(0) && x + trimmed > width {
+				self.lines.push(std::mem::take(&mut line));
+				x = Mm::from(0);
+				if word.s.s.trim().is_empty() {
+					continue
+				}
+			}
+			if trimmed > width {
+				// the word is longer than a full line, so split it between characters
+				let mut part = String::new();
+				for c in word.s.s.chars() {
+					let w = word_style.char_width(&context.font_cache, c);
+					if x + w > width && !part.is_empty() {
+						push_merged(&mut line, &word, std::mem::take(&mut part));
+						self.lines.push(std::mem::take(&mut line));
+						x = Mm::from(0);
+					}
+					part.push(c);
+					x += w
+				}
+				push_merged(&mut line, &word, part);
+				continue
+			}
+			x += word_style.str_width(&context.font_cache, &word.s.s);
+			push_merged(&mut line, &word, word.s.s.clone());
+		}
+		if !line.is_empty() {
+			self.lines.push(line)
+		}
+	}
+}
+
+impl Element for TextBlock {
+	fn render(&mut self, context: &Context, area: Area<'_>, style: Style) -> Result<RenderResult, Error> {
+		let mut result = RenderResult::default();
+		if self.render_idx == 0 && self.lines.is_empty() {
+			self.layout(area.size().width, context, style)
+		}
+		let end = self.fitting_lines(area.size().height, context, style);
+		let mut y = Mm::from(0);
+		while let Some(line) = self.lines.get(self.render_idx) {
+			let line_height = line_height(line, context, style);
+			if self.render_idx == end {
+				result.has_more = true;
+				break
+			}
+			// all spans share the baseline of the text section, which uses the paragraph style
+			let baseline = y + style.font(&context.font_cache).glyph_height(style.font_size());
+			let widths = line
+				.iter()
+				.enumerate()
+				.map(|(i, span)| {
+					let text = if i + 1 == line.len() {
+						span.s.s.trim_end()
+					} else {
+						&span.s.s
+					};
+					style.and(span.s.style).str_width(&context.font_cache, text)
+				})
+				.collect::<Vec<_>>();
+			let line_width = widths.iter().fold(Mm::from(0), |a, b| a + *b);
+			let offset = match self.alignment {
+				Alignment::Left => Mm::from(0),
+				Alignment::Center => (area.size().width - line_width) / 2.0,
+				Alignment::Right => area.size().width - line_width,
+			};
+			let mut x = offset;
+			for (span, width) in line.iter().zip(widths) {
+				let span_style = style.and(span.s.style);
+				if let Some(background) = span.background {
+					let (ascent, descent) = font_metrics(context, span_style);
+					fill_rect(
+						&area,
+						Position::new(x, baseline - ascent),
+						Size::new(width, ascent + descent),
+						background,
+					)
+				}
+				x += width
+			}
+			if let Some(mut section) = area.text_section(&context.font_cache, Position::new(offset, y), style) {
+				for span in line {
+					section.print_str(&span.s.s, style.and(span.s.style))?;
+				}
+			}
+			result.size = result.size.stack_vertical(Size::new(x, line_height));
+			y += line_height;
+			self.render_idx += 1;
+		}
+		Ok(result)
+	}
+}
+
+impl Measure for TextBlock {
+	fn height(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		if self.render_idx == 0 && self.lines.is_empty() {
+			self.layout(width, context, style)
+		}
+		self.lines[self.render_idx..]
+			.iter()
+			.map(|line| line_height(line, context, style))
+			.sum()
+	}
+
+	fn lead(&mut self, width: Mm, context: &Context, style: Style) -> Mm {
+		if self.render_idx == 0 && self.lines.is_empty() {
+			self.layout(width, context, style)
+		}
+		let end = (self.render_idx + self.orphans.max(1)).min(self.lines.len());
+		self.lines[self.render_idx..end]
+			.iter()
+			.map(|line| line_height(line, context, style))
+			.sum()
+	}
+}
+
+/// Height of a line, which is the line height of its tallest span
+fn line_height(line: &[Span], context: &Context, style: Style) -> Mm {
+	line.iter()
+		.map(|s| style.and(s.s.style).line_height(&context.font_cache))
+		.fold(style.line_height(&context.font_cache), |a, b| if b > a { b } else { a })
+}
+
+/// Split spans into words. Whitespace is kept at the end of each word, like genpdf does, except
+/// for line breaks which are words on their own. Lines aren't broken at non-breaking spaces
+fn words(spans: &[Span]) -> Vec<Span> {
+	let mut out = Vec::new();
+	for span in spans {
+		let mut word = String::new();
+		for c in span.s.s.chars() {
+			if (c == '\n' && !word.is_empty()) || (!is_break(c) && word.ends_with(is_break)) {
+				out.push(Span {
+					s: StyledString::new(std::mem::take(&mut word), span.s.style),
+					background: span.background,
+				})
+			}
+			if c == '\n' {
+				out.push(Span {
+					s: StyledString::new("\n", span.s.style),
+					background: None,
+				});
+				continue
+			}
+			word.push(c)
+		}
+		if !word.is_empty() {
+			out.push(Span {
+				s: StyledString::new(word, span.s.style),
+				background: span.background,
+			})
+		}
+	}
+	out
+}
+
+/// Push text to a line, merging it with the last span if it has the same style and background
+fn push_merged(line: &mut Vec<Span>, like: &Span, text: String) {
+	match line.last_mut() {
+		Some(last) if last.s.style == like.s.style && last.background == like.background => last.s.s.push_str(&text),
+		_ => line.push(Span {
+			s: StyledString::new(text, like.s.style),
+			background: like.background,
+		}),
+	}
+}
+
+/// Checks if a line can be broken after a character
+fn is_break(c: char) -> bool { c.is_whitespace() && !matches!(c, '\u{a0}' | '\u{2007}' | '\u{202f}') }
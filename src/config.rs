@@ -1,7 +1,10 @@
-use serde::Deserialize;
+use genpdf::style::Color;
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::html;
 
 /// Root config struct
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct Config {
 	#[serde(default = "FontSize::default")]
 	pub font_size: FontSize,
@@ -10,7 +13,25 @@ pub struct Config {
 	pub page: PageOpts,
 	#[serde(default = "Highlight::default")]
 	pub highlight: Highlight,
+	#[serde(rename = "print-mode", default = "PrintMode::default")]
+	pub print_mode: PrintMode,
+	#[serde(default = "CodeOpts::default")]
+	pub code: CodeOpts,
+	#[serde(default = "TableOpts::default")]
+	pub table: TableOpts,
+	#[serde(default = "KeepOpts::default")]
+	pub keep: KeepOpts,
+	#[serde(default = "HeadingStyles::default")]
+	pub style: HeadingStyles,
+	#[serde(default = "TextStyle::default")]
+	pub text: TextStyle,
+	#[serde(default = "LinkStyle::default")]
+	pub link: LinkStyle,
+	#[serde(default = "QuoteStyle::default")]
+	pub quote: QuoteStyle,
 	pub subtitle: Option<String>,
+	#[serde(default = "default_jobs")]
+	pub jobs: usize,
 }
 
 /// Optional custom fonts
@@ -26,14 +47,27 @@ pub struct Fonts {
 
 /// Highlighting settings
 #[allow(non_camel_case_types)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub enum Highlight {
+	#[default]
 	all,
 	#[serde(rename = "no-node")]
 	no_node,
 	none,
 }
 
+/// Colours used in the PDF
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PrintMode {
+	#[default]
+	#[serde(alias = "color")]
+	colour,
+	/// Every colour is turned to grey, with code tokens told apart by bold, italic and shade
+	#[serde(alias = "greyscale")]
+	grayscale,
+}
+
 /// Font sizes for title, H1 to H6, and text
 #[derive(Deserialize, Debug)]
 pub struct FontSize {
@@ -57,9 +91,10 @@ pub struct FontSize {
 
 /// Page size enum
 #[allow(non_camel_case_types)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 #[serde(untagged)]
 pub enum PageSize {
+	#[default]
 	A4,
 	#[serde(rename = "US letter")]
 	US_letter,
@@ -95,6 +130,216 @@ pub struct PageOpts {
 	pub spacing: PageSpaces,
 }
 
+/// Code block options
+#[derive(Deserialize, Debug)]
+pub struct CodeOpts {
+	#[serde(rename = "line-numbers", default = "default_line_numbers")]
+	pub line_numbers: bool,
+	#[serde(
+		rename = "highlight-colour",
+		alias = "highlight-color",
+		default = "default_highlight_colour"
+	)]
+	pub highlight_colour: Colour,
+	#[serde(default = "CodeBackground::default")]
+	pub background: CodeBackground,
+	#[serde(default = "default_code_border")]
+	pub border: bool,
+	#[serde(
+		rename = "border-colour",
+		alias = "border-color",
+		default = "default_code_border_colour"
+	)]
+	pub border_colour: Colour,
+	#[serde(default = "default_code_padding")]
+	pub padding: f64,
+	#[serde(default = "Overflow::default")]
+	pub overflow: Overflow,
+	#[serde(rename = "tab-width", default = "default_tab_width")]
+	pub tab_width: usize,
+	#[serde(rename = "inline-language", default, deserialize_with = "non_empty")]
+	pub inline_language: Option<String>,
+	#[serde(rename = "inline-background", default = "CodeBackground::default")]
+	pub inline_background: CodeBackground,
+	#[serde(rename = "diff-added", default = "default_diff_added")]
+	pub diff_added: Colour,
+	#[serde(rename = "diff-removed", default = "default_diff_removed")]
+	pub diff_removed: Colour,
+}
+
+/// Table options
+#[derive(Deserialize, Debug)]
+pub struct TableOpts {
+	#[serde(rename = "header-background", default = "Background::default")]
+	pub header_background: Background,
+	#[serde(rename = "stripe-background", default = "Background::default")]
+	pub stripe_background: Background,
+	#[serde(rename = "inner-borders", default = "Borders::default")]
+	pub inner_borders: Borders,
+	#[serde(rename = "outer-border", default = "default_table_outer_border")]
+	pub outer_border: bool,
+	#[serde(
+		rename = "border-colour",
+		alias = "border-color",
+		default = "default_table_border_colour"
+	)]
+	pub border_colour: Colour,
+	#[serde(rename = "cell-padding", default = "default_cell_padding")]
+	pub cell_padding: (f64, f64),
+	#[serde(rename = "font-size")]
+	pub font_size: Option<u8>,
+}
+
+/// Page break control options
+#[derive(Deserialize, Debug)]
+pub struct KeepOpts {
+	#[serde(default = "default_keep")]
+	pub headings: bool,
+	#[serde(default = "default_keep")]
+	pub code: bool,
+	#[serde(default = "default_keep")]
+	pub tables: bool,
+	#[serde(rename = "list-items", default = "default_keep")]
+	pub list_items: bool,
+	#[serde(rename = "max-height", default = "default_keep_max_height")]
+	pub max_height: f64,
+	#[serde(default = "default_orphans")]
+	pub orphans: usize,
+	#[serde(default = "default_widows")]
+	pub widows: usize,
+}
+
+/// Body text style. Also used for the title page and contents
+#[derive(Deserialize, Debug, Default)]
+pub struct TextStyle {
+	#[serde(alias = "color")]
+	pub colour: Option<Colour>,
+}
+
+/// Link text style. Links can't be followed in the PDF, so this only changes how they look
+#[derive(Deserialize, Debug, Default)]
+pub struct LinkStyle {
+	#[serde(alias = "color")]
+	pub colour: Option<Colour>,
+	#[serde(default = "default_link_bold")]
+	pub bold: bool,
+	#[serde(default = "default_link_italic")]
+	pub italic: bool,
+}
+
+/// Block quote style. Quotes are drawn with a bar down the left side
+#[derive(Deserialize, Debug)]
+pub struct QuoteStyle {
+	#[serde(alias = "color")]
+	pub colour: Option<Colour>,
+	#[serde(default = "default_quote_italic")]
+	pub italic: bool,
+	#[serde(rename = "bar-colour", alias = "bar-color", default = "default_quote_bar_colour")]
+	pub bar_colour: Colour,
+}
+
+/// Styles for each heading level
+#[derive(Deserialize, Debug, Default)]
+pub struct HeadingStyles {
+	#[serde(default = "HeadingStyle::default")]
+	pub h1: HeadingStyle,
+	#[serde(default = "HeadingStyle::default")]
+	pub h2: HeadingStyle,
+	#[serde(default = "HeadingStyle::default")]
+	pub h3: HeadingStyle,
+	#[serde(default = "HeadingStyle::default")]
+	pub h4: HeadingStyle,
+	#[serde(default = "HeadingStyle::default")]
+	pub h5: HeadingStyle,
+	#[serde(default = "HeadingStyle::default")]
+	pub h6: HeadingStyle,
+}
+
+/// Heading style. The space before a heading defaults to `page.spacing.heading` for H1 and 0 for
+/// the others
+#[derive(Deserialize, Debug)]
+pub struct HeadingStyle {
+	#[serde(alias = "color")]
+	pub colour: Option<Colour>,
+	#[serde(default = "default_heading_bold")]
+	pub bold: bool,
+	#[serde(default = "default_heading_italic")]
+	pub italic: bool,
+	#[serde(default = "TextAlign::default")]
+	pub align: TextAlign,
+	#[serde(rename = "space-before")]
+	pub space_before: Option<f64>,
+	#[serde(rename = "space-after", default = "default_heading_space_after")]
+	pub space_after: f64,
+	#[serde(rename = "rule-below", default = "default_rule_below")]
+	pub rule_below: bool,
+	#[serde(rename = "page-break-before", default = "default_page_break_before")]
+	pub page_break_before: bool,
+}
+
+/// Horizontal text alignment
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TextAlign {
+	#[default]
+	left,
+	center,
+	right,
+}
+
+/// Borders drawn between table cells
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Borders {
+	/// Between all rows and columns
+	#[default]
+	all,
+	/// Only between rows
+	rows,
+	/// Only between columns
+	columns,
+	none,
+}
+
+/// How code lines that are too long for the page are handled
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+	/// Wrap onto continuation rows
+	#[default]
+	wrap,
+	/// Shrink the block font size to fit the longest line
+	shrink,
+	/// Cut lines off with an ellipsis
+	clip,
+}
+
+/// Code block background. Either the highlighting theme background, no background, or a colour
+#[derive(Debug, Default)]
+pub enum CodeBackground {
+	#[default]
+	Theme,
+	None,
+	Colour(Colour),
+}
+
+/// Optional background colour. Either no background or a colour
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Background {
+	#[default]
+	None,
+	Colour(Colour),
+}
+
+/// RGB colour given as a hex string (`"#rrggbb"` or `"#rgb"`)
+#[derive(Debug, Clone, Copy)]
+pub struct Colour(pub Color);
+
+/// Code background for `"theme"` when the highlighting theme doesn't have one, or highlighting
+/// isn't done with syntect
+pub const DEFAULT_CODE_BACKGROUND: Color = Color::Rgb(246, 248, 250);
+
+fn default_jobs() -> usize { 0 }
 fn default_title() -> u8 { 25 }
 fn default_h1() -> u8 { 22 }
 fn default_h2() -> u8 { 20 }
@@ -108,10 +353,30 @@ fn default_heading_space() -> f64 { 3.0 }
 fn default_margin() -> (f64, f64) { (20.0, 20.0) }
 fn default_landscape() -> bool { false }
 fn default_new_pages() -> bool { true }
-
-impl Config {
-	pub fn from_rc(rc: Option<Self>) -> Self { rc.unwrap_or(Self::default()) }
-}
+fn default_line_numbers() -> bool { false }
+fn default_highlight_colour() -> Colour { Colour(Color::Rgb(255, 244, 184)) }
+fn default_code_border() -> bool { false }
+fn default_code_border_colour() -> Colour { Colour(Color::Rgb(204, 204, 204)) }
+fn default_code_padding() -> f64 { 2.0 }
+fn default_tab_width() -> usize { 4 }
+fn default_diff_added() -> Colour { Colour(Color::Rgb(230, 255, 236)) }
+fn default_diff_removed() -> Colour { Colour(Color::Rgb(255, 235, 233)) }
+fn default_table_outer_border() -> bool { false }
+fn default_table_border_colour() -> Colour { Colour(Color::Rgb(0, 0, 0)) }
+fn default_cell_padding() -> (f64, f64) { (1.0, 2.0) }
+fn default_link_bold() -> bool { false }
+fn default_link_italic() -> bool { false }
+fn default_quote_italic() -> bool { false }
+fn default_quote_bar_colour() -> Colour { Colour(Color::Rgb(204, 204, 204)) }
+fn default_heading_bold() -> bool { false }
+fn default_heading_italic() -> bool { false }
+fn default_heading_space_after() -> f64 { 3.0 }
+fn default_rule_below() -> bool { false }
+fn default_page_break_before() -> bool { false }
+fn default_keep() -> bool { true }
+fn default_keep_max_height() -> f64 { 120.0 }
+fn default_orphans() -> usize { 2 }
+fn default_widows() -> usize { 2 }
 
 impl PageSize {
 	/// Get page size. Requires landscape bool
@@ -130,6 +395,64 @@ impl PageSize {
 	}
 }
 
+impl Colour {
+	/// Parse a hex colour string. The leading `#` is optional
+	pub fn parse(s: &str) -> Option<Self> {
+		let (r, g, b) = html::parse_hex(s.trim().trim_start_matches('#'))?;
+		Some(Self(Color::Rgb(r, g, b)))
+	}
+}
+
+impl<'de> Deserialize<'de> for Colour {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let raw = String::deserialize(deserializer)?;
+		Self::parse(&raw).ok_or_else(|| D::Error::custom(format!("invalid colour \"{}\"", raw)))
+	}
+}
+
+impl<'de> Deserialize<'de> for CodeBackground {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let raw = String::deserialize(deserializer)?;
+		match &*raw {
+			"theme" => Ok(Self::Theme),
+			"none" => Ok(Self::None),
+			_ => Colour::parse(&raw).map(Self::Colour).ok_or_else(|| {
+				D::Error::custom(format!(
+					"invalid code background \"{}\", expected \"theme\", \"none\", or a colour",
+					raw
+				))
+			}),
+		}
+	}
+}
+
+impl Background {
+	/// Get the colour, if there is one
+	pub fn colour(&self) -> Option<Color> {
+		match self {
+			Self::None => None,
+			Self::Colour(c) => Some(c.0),
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for Background {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let raw = String::deserialize(deserializer)?;
+		match &*raw {
+			"none" => Ok(Self::None),
+			_ => Colour::parse(&raw).map(Self::Colour).ok_or_else(|| {
+				D::Error::custom(format!("invalid background \"{}\", expected \"none\" or a colour", raw))
+			}),
+		}
+	}
+}
+
+/// Read an optional string, where an empty string is the same as not setting it
+fn non_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+	Ok(Some(String::deserialize(deserializer)?).filter(|s| !s.is_empty()))
+}
+
 impl FontSize {
 	/// Get the text size for a given ID (from HTML tags)
 	pub fn get(&self, section: &str) -> u8 {
@@ -145,22 +468,20 @@ impl FontSize {
 	}
 }
 
-impl Default for Config {
-	fn default() -> Self {
-		Self {
-			font_size: Default::default(),
-			font: None,
-			page: Default::default(),
-			highlight: Default::default(),
-			subtitle: None,
+impl HeadingStyles {
+	/// Get the style for a heading level. Any heading after H6 uses the H6 style
+	pub fn get(&self, level: u8) -> &HeadingStyle {
+		match level {
+			1 => &self.h1,
+			2 => &self.h2,
+			3 => &self.h3,
+			4 => &self.h4,
+			5 => &self.h5,
+			_ => &self.h6,
 		}
 	}
 }
 
-impl Default for Highlight {
-	fn default() -> Self { Self::all }
-}
-
 impl Default for FontSize {
 	fn default() -> Self {
 		Self {
@@ -176,10 +497,6 @@ impl Default for FontSize {
 	}
 }
 
-impl Default for PageSize {
-	fn default() -> Self { PageSize::A4 }
-}
-
 impl Default for PageSpaces {
 	fn default() -> Self {
 		Self {
@@ -200,3 +517,75 @@ impl Default for PageOpts {
 		}
 	}
 }
+
+impl Default for CodeOpts {
+	fn default() -> Self {
+		Self {
+			line_numbers: default_line_numbers(),
+			highlight_colour: default_highlight_colour(),
+			background: Default::default(),
+			border: default_code_border(),
+			border_colour: default_code_border_colour(),
+			padding: default_code_padding(),
+			overflow: Default::default(),
+			tab_width: default_tab_width(),
+			inline_language: None,
+			inline_background: Default::default(),
+			diff_added: default_diff_added(),
+			diff_removed: default_diff_removed(),
+		}
+	}
+}
+
+impl Default for TableOpts {
+	fn default() -> Self {
+		Self {
+			header_background: Default::default(),
+			stripe_background: Default::default(),
+			inner_borders: Default::default(),
+			outer_border: default_table_outer_border(),
+			border_colour: default_table_border_colour(),
+			cell_padding: default_cell_padding(),
+			font_size: None,
+		}
+	}
+}
+
+impl Default for KeepOpts {
+	fn default() -> Self {
+		Self {
+			headings: default_keep(),
+			code: default_keep(),
+			tables: default_keep(),
+			list_items: default_keep(),
+			max_height: default_keep_max_height(),
+			orphans: default_orphans(),
+			widows: default_widows(),
+		}
+	}
+}
+
+impl Default for HeadingStyle {
+	fn default() -> Self {
+		Self {
+			colour: None,
+			bold: default_heading_bold(),
+			italic: default_heading_italic(),
+			align: Default::default(),
+			space_before: None,
+			space_after: default_heading_space_after(),
+			rule_below: default_rule_below(),
+			page_break_before: default_page_break_before(),
+		}
+	}
+}
+
+impl Default for QuoteStyle {
+	fn default() -> Self {
+		Self {
+			colour: None,
+			italic: default_quote_italic(),
+			bar_colour: default_quote_bar_colour(),
+		}
+	}
+}
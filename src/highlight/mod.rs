@@ -1,3 +1,23 @@
+use genpdf::style::{Color, Style, StyledString};
+
 pub mod node;
 pub mod syntect;
 pub mod util;
+
+/// Styled string with an optional background colour. Highlighted code is made of these, and
+/// paragraphs use them for inline code
+#[derive(Clone, Debug)]
+pub struct Span {
+	pub s: StyledString,
+	pub background: Option<Color>,
+}
+
+impl Span {
+	/// Create a new span without a background
+	pub fn new(s: impl Into<String>, style: impl Into<Style>) -> Self {
+		Self {
+			s: StyledString::new(s, style),
+			background: None,
+		}
+	}
+}
@@ -1,53 +1,42 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::BTreeMap;
 
 use anyhow::Error;
-use genpdf::{
-	elements::{LinearLayout, Paragraph},
-	style::{Color, Style},
-};
+use genpdf::style::{Color, Style};
 
-use crate::highlight::util::{to_block, StyleElement};
+use crate::highlight::{
+	util::{plain_lines, to_lines, StyleElement},
+	Span,
+};
 
 /// Checks if the used highlight_.js script can highlight a specific language. Used before calling
 /// [highlight] to check that the Js won't fail because it can't highlight the language
-fn check_language(names: HashSet<String>, hl: &String) -> Option<String> {
-	for name in names {
-		if name.starts_with("language-") {
-			match std::process::Command::new("node")
-				.arg("-e")
-				.arg(format!(
-					"{};console.log(!!hljs.getLanguage({:?}))",
-					hl,
-					&name[9..name.len()]
-				))
-				.output()
-			{
-				Ok(out) => {
-					let stdout = String::from_utf8(out.stdout).unwrap();
-					if out.status.code() != Some(0) {
-						println!(
-							"Error in getting highlight_.js languages! {}",
-							String::from_utf8(out.stderr).unwrap()
-						);
-						std::process::exit(1)
-					}
-					if stdout.trim() == "true" {
-						return Some(name[9..name.len()].to_string())
-					}
-				}
-				Err(e) => {
-					mdbook::utils::log_backtrace(&Error::new(e));
-					std::process::exit(1)
-				}
+fn check_language(language: &str, hl: &String) -> bool {
+	match std::process::Command::new("node")
+		.arg("-e")
+		.arg(format!("{};console.log(!!hljs.getLanguage({:?}))", hl, language))
+		.output()
+	{
+		Ok(out) => {
+			let stdout = String::from_utf8(out.stdout).unwrap();
+			if out.status.code() != Some(0) {
+				println!(
+					"Error in getting highlight_.js languages! {}",
+					String::from_utf8(out.stderr).unwrap()
+				);
+				std::process::exit(1)
 			}
+			stdout.trim() == "true"
+		}
+		Err(e) => {
+			mdbook::utils::log_backtrace(&Error::new(e));
+			std::process::exit(1)
 		}
 	}
-	None
 }
 
 /// Highlights a section of code using highlight_.js
-pub fn highlight(classes: HashSet<String>, hl: &String, src: String) -> LinearLayout {
-	if let Some(lang_name) = check_language(classes, hl) {
+pub fn highlight(language: &str, hl: &String, src: String) -> Vec<Vec<Span>> {
+	if check_language(language, hl) {
 		let raw = match std::process::Command::new("node")
 			.current_dir(std::env::current_dir().unwrap())
 			.args([
@@ -55,8 +44,12 @@ pub fn highlight(classes: HashSet<String>, hl: &String, src: String) -> LinearLa
 				&*format!(
 					"{};console.log(hljs.highlight('{}',{{language:'{}'}}).value)",
 					hl,
-					src.replace("'", "\\'").replace("\"", "\\\"").replace("\n", "\\n"),
-					lang_name
+					src.replace("\\", "\\\\")
+						.replace("'", "\\'")
+						.replace("\"", "\\\"")
+						.replace("\r", "")
+						.replace("\n", "\\n"),
+					language
 				),
 			])
 			.output()
@@ -114,22 +107,14 @@ pub fn highlight(classes: HashSet<String>, hl: &String, src: String) -> LinearLa
 				.map(|(n, c)| (n.to_string(), StyleElement::Child(Style::new().with_color(c)))),
 			),
 		};
-		to_block(raw, &colour_map, |t| {
-			let mut iter = t.split_whitespace().enumerate();
-			let mut out = Vec::new();
-			if let Some((_, f)) = iter.next() {
-				out.push(f[5..f.len()].to_string())
-			}
-			for (l, f) in iter {
-				out.push(f[..f.len() - l].to_string())
-			}
-			out
+		// highlight.js classes are `hljs-<scope>` for the first class, with sub-scopes marked
+		// with trailing underscores (`function_`)
+		to_lines(raw, &colour_map, |t| {
+			t.split_whitespace()
+				.map(|c| c.strip_prefix("hljs-").unwrap_or(c).trim_end_matches('_').to_string())
+				.collect()
 		})
 	} else {
-		let mut block = LinearLayout::vertical();
-		for line in src.lines() {
-			block.push(Paragraph::new(line))
-		}
-		block
+		plain_lines(&src)
 	}
 }
@@ -1,42 +1,108 @@
-use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
-use genpdf::elements::{LinearLayout, Paragraph};
+use genpdf::style::{Color, Style, StyledString};
 use syntect::{
-	html::{ClassStyle, ClassedHTMLGenerator},
+	easy::HighlightLines,
+	highlighting::{FontStyle, Theme},
 	parsing::SyntaxSet,
 	util::LinesWithEndings,
 };
 
-use crate::highlight::util::{to_block, StyleElement};
+use crate::highlight::{util::plain_lines, Span};
 
-pub fn highlight(classes: HashSet<String>, src: String, ss: &SyntaxSet, theme: &StyleElement) -> LinearLayout {
-	let mut syntax = None;
-	let mut block = LinearLayout::vertical();
-	for class in classes.iter() {
-		if class.starts_with("language-") {
-			if let Some(found_syntax) = ss
-				.syntaxes()
-				.iter()
-				.rev()
-				.find(|&s| class[9..class.len()] == s.name.to_lowercase())
-			{
-				syntax = Some((found_syntax, ss, theme));
-				break
+/// Get the background colour of a syntect theme, if it sets one
+pub fn theme_background(theme: &Theme) -> Option<Color> { theme.settings.background.map(|c| Color::Rgb(c.r, c.g, c.b)) }
+
+/// Load the syntax set used for syntect highlighting\
+/// This starts from the syntect defaults, or the bundled extended syntaxes (a superset of the
+/// defaults) when the `extended-syntaxes` feature is enabled. Any `.sublime-syntax` files in the
+/// theme folder are then added on top. Syntaxes are searched in reverse order, so the theme folder
+/// syntaxes take precedence over the bundled ones
+pub fn load_syntaxes(theme: &Path) -> SyntaxSet {
+	#[cfg(feature = "extended-syntaxes")]
+	let ss = two_face::syntax::extra_newlines();
+	#[cfg(not(feature = "extended-syntaxes"))]
+	let ss = SyntaxSet::load_defaults_newlines();
+	if !theme.is_dir() {
+		return ss
+	}
+	let mut ss = ss.into_builder();
+	if let Err(e) = ss.add_from_folder(theme, true) {
+		println!("Unable to load syntax files from theme folder: {}", e)
+	};
+	ss.build()
+}
+
+/// Contents of the syntax files in the theme folder (and its subfolders), sorted by path. Used to
+/// tell if the syntax set has changed for the highlight cache
+pub fn syntax_sources(theme: &Path) -> Vec<Vec<u8>> {
+	fn walk(dir: &Path, out: &mut Vec<PathBuf>) {
+		if let Ok(entries) = std::fs::read_dir(dir) {
+			for entry in entries.flatten() {
+				let path = entry.path();
+				if path.is_dir() {
+					walk(&path, out)
+				} else if path.extension().is_some_and(|e| e == "sublime-syntax") {
+					out.push(path)
+				}
 			}
 		}
 	}
-	if let Some((syntax, syntax_set, theme)) = syntax {
-		let mut parser = ClassedHTMLGenerator::new_with_class_style(syntax, syntax_set, ClassStyle::Spaced);
-		for line in LinesWithEndings::from(&*src) {
-			parser.parse_html_for_line_which_includes_newline(line).unwrap();
+	let mut paths = Vec::new();
+	walk(theme, &mut paths);
+	paths.sort();
+	paths.iter().filter_map(|p| std::fs::read(p).ok()).collect()
+}
+
+/// Highlights a section of code using syntect. Styles come straight from the theme, so scope
+/// selector specificity and exclusions work the same way as they do in syntect\
+/// Tokens with a background other than the theme background keep it as a span background
+pub fn highlight(language: &str, src: String, ss: &SyntaxSet, theme: &Theme) -> Vec<Vec<Span>> {
+	if let Some(syntax) = ss.syntaxes().iter().rev().find(|&s| language == s.name.to_lowercase()) {
+		let mut highlighter = HighlightLines::new(syntax, theme);
+		let mut lines = Vec::new();
+		for line in LinesWithEndings::from(&src) {
+			match highlighter.highlight_line(line, ss) {
+				Ok(ranges) => lines.push(
+					ranges
+						.into_iter()
+						.map(|(style, text)| (style, text.trim_end_matches(['\n', '\r'])))
+						.filter(|(_, text)| !text.is_empty())
+						.map(|(style, text)| to_span(text, style, theme))
+						.collect(),
+				),
+				Err(e) => {
+					println!("Error highlighting code! Using un-highlighted code. {}", e);
+					return plain_lines(&src)
+				}
+			}
 		}
-		to_block(parser.finalize(), theme, |t| {
-			t.split_whitespace().map(|l| l.to_string()).collect()
-		})
+		lines
 	} else {
-		for line in src.lines() {
-			block.push(Paragraph::new(line))
-		}
-		block
+		plain_lines(&src)
+	}
+}
+
+/// Convert a token with a syntect style into a span. The background is only kept if it isn't the
+/// theme background, which is already drawn behind the whole block\
+/// Underlines (`FontStyle::UNDERLINE`) aren't supported, because genpdf can't draw underlined
+/// text, so underlined tokens only keep their colour
+fn to_span(text: &str, style: syntect::highlighting::Style, theme: &Theme) -> Span {
+	let c = style.foreground;
+	let mut out = Style::new().with_color(Color::Rgb(c.r, c.g, c.b));
+	if style.font_style.contains(FontStyle::BOLD) {
+		out.set_bold()
+	}
+	if style.font_style.contains(FontStyle::ITALIC) {
+		out.set_italic()
+	}
+	let background = style.background;
+	Span {
+		s: StyledString::new(text, out),
+		background: (Some(background) != theme.settings.background && background.a > 0).then_some(Color::Rgb(
+			background.r,
+			background.g,
+			background.b,
+		)),
 	}
 }
@@ -1,18 +1,13 @@
 use std::{
 	collections::BTreeMap,
 	fmt::{Debug, Formatter},
-	iter::Peekable,
-	slice::Iter,
-	str::Chars,
 };
 
-use genpdf::{
-	elements::{LinearLayout, Paragraph},
-	style::{Color, Style, StyledString},
-};
-use syntect::highlighting::ThemeItem;
+use ego_tree::NodeRef;
+use genpdf::style::Style;
+use scraper::{Html, Node};
 
-use crate::build::sections::replace_reserved;
+use crate::highlight::Span;
 
 /// Simplified HTML token. Can either be raw text, or an element with children and classes
 #[derive(Debug)]
@@ -29,12 +24,14 @@ impl Token {
 		match self {
 			Token::Text(t) => out_ref.push((t, style)),
 			Token::Element { classes, children } => {
+				// elements without classes (e.g. formatting from plugins) keep the parent style
+				let style = if classes.is_empty() {
+					style
+				} else {
+					style_map.get_style(classes.iter().map(|c| c.as_str()))
+				};
 				for child in children {
-					child.expand(
-						out_ref,
-						style_map.get_style(classes.iter().map(|c| c.as_str())),
-						style_map,
-					)
+					child.expand(out_ref, style, style_map)
 				}
 			}
 		}
@@ -59,51 +56,13 @@ impl StyleElement {
 					if let Some(child) = children.get(class) {
 						child.get_style(classes)
 					} else {
-						default.clone()
+						*default
 					}
 				} else {
-					default.clone()
+					*default
 				}
 			}
-			StyleElement::Child(s) => s.clone(),
-		}
-	}
-
-	/// Insert a key into the style map
-	pub fn insert(self, mut path: Iter<&str>, style: Style) -> Self {
-		if let Some(next) = path.next() {
-			if path.len() == 0 {
-				match self {
-					StyleElement::Parent { mut children, default } => {
-						children.insert(next.to_string(), Self::Child(style));
-						StyleElement::Parent { children, default }
-					}
-					StyleElement::Child(_) => Self::Child(style),
-				}
-			} else {
-				match self {
-					StyleElement::Parent { mut children, default } => {
-						if let Some(continuation) = children.remove(next.clone()) {
-							children.insert(next.to_string(), continuation.insert(path, style));
-						} else {
-							let mut new_child = Self::Child(style);
-							new_child = new_child.insert(path, style);
-							children.insert(next.to_string(), new_child);
-						}
-						StyleElement::Parent { children, default }
-					}
-					StyleElement::Child(s) => {
-						let mut new_child = Self::Child(s.clone());
-						new_child = new_child.insert(path, style);
-						Self::Parent {
-							default: s.clone(),
-							children: BTreeMap::from([(next.to_string(), new_child)]),
-						}
-					}
-				}
-			}
-		} else {
-			self
+			StyleElement::Child(s) => *s,
 		}
 	}
 }
@@ -124,142 +83,52 @@ impl Debug for StyleElement {
 	}
 }
 
-impl From<Vec<ThemeItem>> for StyleElement {
-	fn from(t: Vec<ThemeItem>) -> Self {
-		let mut out = Self::Parent {
-			default: Style::new(),
-			children: BTreeMap::new(),
-		};
-		for i in t {
-			let mut style = Style::new();
-			if let Some(c) = i.style.foreground {
-				style.set_color(Color::Rgb(c.r, c.g, c.b))
-			}
-			if let Some(fs) = i.style.font_style {
-				if (fs.bits() & 1) == 1 {
-					style.set_bold()
-				}
-				if (fs.bits() & 4) == 4 {
-					style.set_italic()
-				}
-			}
-			for selector in i.scope.selectors {
-				for scope in selector.path.scopes {
-					let scope = scope.build_string();
-					out = out.insert(scope.split(".").collect::<Vec<_>>().iter(), style);
-				}
-			}
-		}
-		out
-	}
-}
-
-/// A bad HTML parser that makes a lot of assumptions. Assumes only <span> tags or text
+/// Convert highlighted HTML into tokens using a real HTML parser, so attribute order, entities,
+/// and escaped characters don't matter. Elements other than `<span>` are treated the same way,
+/// with their classes passed through the class map function
 fn parse_html(src: String, map: fn(String) -> Vec<String>) -> Vec<Token> {
-	let mut iter = src.chars().peekable();
-	fn inner(i: &mut Peekable<Chars>, map: fn(String) -> Vec<String>) -> Vec<Token> {
-		let mut out = Vec::new();
-		loop {
-			match i.next() {
-				None => break,
-				Some('<') => {
-					// found a <span ...>
-					for _ in 0..4 {
-						i.next();
-					}
-					match i.next() {
-						Some('>') => out.push(Token::Element {
-							classes: Vec::new(),
-							children: inner(i, map),
-						}),
-						Some('n') => break,
-						_ => {
-							// parse classes
-							for _ in 0..7 {
-								i.next();
-							}
-							let mut classes_raw = String::new();
-							while let Some(c) = i.peek() {
-								if c == &'"' {
-									break
-								}
-								classes_raw.push(i.next().unwrap())
-							}
-							// skip closing ">
-							for _ in 0..2 {
-								i.next();
-							}
-							out.push(Token::Element {
-								classes: map(classes_raw),
-								children: inner(i, map),
-							});
-						}
-					}
-					// skip ending >
-					i.next();
-				}
-				Some(c) => {
-					let mut text = String::from(c);
-					while let Some(c) = i.peek() {
-						if c == &'<' {
-							break
-						}
-						text.push(i.next().unwrap())
-					}
-					out.push(Token::Text(replace_reserved(text)))
-				}
-			}
-		}
-		out
+	fn inner(node: NodeRef<Node>, map: fn(String) -> Vec<String>) -> Vec<Token> {
+		node.children()
+			.filter_map(|child| match child.value() {
+				Node::Text(t) => Some(Token::Text(t.to_string())),
+				Node::Element(e) => Some(Token::Element {
+					classes: e.attr("class").map(|c| map(c.to_string())).unwrap_or_default(),
+					children: inner(child, map),
+				}),
+				_ => None,
+			})
+			.collect()
 	}
-	inner(&mut iter, map)
+	let fragment = Html::parse_fragment(&src);
+	inner(*fragment.root_element(), map)
 }
 
-pub fn to_block(raw: String, colour_map: &StyleElement, f: fn(String) -> Vec<String>) -> LinearLayout {
+/// Split highlighted HTML into lines of spans
+pub fn to_lines(raw: String, colour_map: &StyleElement, f: fn(String) -> Vec<String>) -> Vec<Vec<Span>> {
 	let tokens = parse_html(raw, f);
 	let mut out = Vec::new();
 	for child in tokens {
-		child.expand(&mut out, Style::new(), &colour_map)
+		child.expand(&mut out, Style::new(), colour_map)
 	}
-	let mut block = LinearLayout::vertical();
-	let mut line = Paragraph::new("");
-	if out.is_empty() {
-		return block
-	}
-	let mut last = out.pop().unwrap();
-	last.0 = last.0.trim_end().to_string();
+	let mut lines = vec![Vec::new()];
 	for (words, style) in out {
-		if words.contains("\n") {
-			if &*words == "\n" {
-				block.push(line);
-				line = Paragraph::new("");
-			} else {
-				let push_end = words.chars().last() == Some('\n');
-				let mut lines = words.split("\n");
-				line.push(StyledString::new(lines.next().unwrap(), style));
-				for section in lines {
-					block.push(line);
-					line = Paragraph::new(StyledString::new(section, style));
-				}
-				if push_end {
-					block.push(line);
-					line = Paragraph::new("");
-				}
+		for (i, section) in words.split('\n').enumerate() {
+			if i > 0 {
+				lines.push(Vec::new())
+			}
+			let section = section.strip_suffix('\r').unwrap_or(section);
+			if !section.is_empty() {
+				lines.last_mut().unwrap().push(Span::new(section, style))
 			}
-		} else {
-			line.push(StyledString::new(words, style))
 		}
 	}
-	if last.0.contains("\n") {
-		let mut lines = last.0.split("\n");
-		line.push(StyledString::new(lines.next().unwrap(), last.1));
-		for section in lines {
-			block.push(line);
-			line = Paragraph::new(StyledString::new(section, last.1));
-		}
-	} else {
-		line.push(StyledString::new(last.0, last.1))
+	// trailing newlines shouldn't add empty lines to the end of the block. Lines with only
+	// whitespace are kept so that whitespace is preserved exactly
+	while lines.len() > 1 && lines.last().is_some_and(|l| l.is_empty()) {
+		lines.pop();
 	}
-	block.push(line);
-	block
+	lines
 }
+
+/// Split un-highlighted code into lines of spans
+pub fn plain_lines(src: &str) -> Vec<Vec<Span>> { src.lines().map(|l| vec![Span::new(l, Style::new())]).collect() }
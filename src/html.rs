@@ -0,0 +1,357 @@
+//! Helpers for raw HTML in chapters
+
+use std::borrow::Cow;
+
+use markup5ever::data::{C1_REPLACEMENTS, NAMED_ENTITIES};
+
+/// Length of the longest entity name (`CounterClockwiseContourIntegral;`)
+const MAX_ENTITY_LEN: usize = 32;
+
+/// Decode named and numeric character references. This uses the full HTML5 entity table (the same
+/// one html5ever uses for highlighted HTML), including legacy entities without a semicolon.
+/// Anything that isn't a character reference is left as it is
+pub fn decode_entities(s: &str) -> Cow<'_, str> {
+	if !s.contains('&') {
+		return Cow::Borrowed(s)
+	}
+	let mut out = String::with_capacity(s.len());
+	let mut rest = s;
+	while let Some(i) = rest.find('&') {
+		out.push_str(&rest[..i]);
+		rest = &rest[i + 1..];
+		match reference(rest) {
+			Some((decoded, len)) => {
+				out.push_str(&decoded);
+				rest = &rest[len..]
+			}
+			None => out.push('&'),
+		}
+	}
+	out.push_str(rest);
+	Cow::Owned(out)
+}
+
+/// Decode a character reference from the text after a `&`. Returns the decoded text and the length
+/// of the reference
+fn reference(s: &str) -> Option<(String, usize)> {
+	if let Some(num) = s.strip_prefix('#') {
+		let (digits, radix, prefix) = match num.strip_prefix(['x', 'X']) {
+			Some(hex) => (hex, 16, 2),
+			None => (num, 10, 1),
+		};
+		let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+		if len == 0 {
+			return None
+		}
+		// out of range values, NULL, and surrogates are replaced, and C1 control characters are
+		// treated as Windows-1252 like browsers do
+		let value = u32::from_str_radix(&digits[..len], radix).unwrap_or(u32::MAX);
+		let c = match value {
+			0x80..=0x9f => C1_REPLACEMENTS[(value - 0x80) as usize].or(char::from_u32(value)),
+			0 => None,
+			_ => char::from_u32(value),
+		}
+		.unwrap_or('\u{fffd}');
+		let semicolon = digits[len..].starts_with(';') as usize;
+		Some((c.to_string(), prefix + len + semicolon))
+	} else {
+		let name = s
+			.find(|c: char| !c.is_ascii_alphanumeric())
+			.unwrap_or(s.len())
+			.min(MAX_ENTITY_LEN);
+		let name = name + s[name..].starts_with(';') as usize;
+		// the longest matching name is used, so `&notin;` isn't read as `&not` followed by `in;`
+		(1..=name).rev().find_map(|len| match NAMED_ENTITIES.get(&s[..len]) {
+			Some(&(c1, c2)) if c1 != 0 => Some((
+				[c1, c2]
+					.into_iter()
+					.filter(|c| *c != 0)
+					.filter_map(char::from_u32)
+					.collect(),
+				len,
+			)),
+			_ => None,
+		})
+	}
+}
+
+/// Raw HTML token. Tag and attribute names are lowercase, and text and attribute values have their
+/// entities decoded
+#[derive(Debug, PartialEq)]
+pub enum Token {
+	Start { name: String, attrs: Vec<(String, String)> },
+	End(String),
+	Text(String),
+}
+
+impl Token {
+	/// Get the value of an attribute of a start tag
+	pub fn attr(&self, attr: &str) -> Option<&str> {
+		match self {
+			Token::Start { attrs, .. } => attrs.iter().find(|(k, _)| k == attr).map(|(_, v)| v.as_str()),
+			_ => None,
+		}
+	}
+
+	/// Checks if a start tag has a class
+	pub fn has_class(&self, class: &str) -> bool {
+		self.attr("class")
+			.is_some_and(|classes| classes.split_whitespace().any(|c| c == class))
+	}
+}
+
+/// Split raw HTML into tokens. Comments, doctypes, and the contents of `<script>` and `<style>`
+/// elements are skipped. Raw HTML in Markdown comes in pieces, so tags don't need to be balanced
+pub fn tokens(raw: &str) -> Vec<Token> {
+	let mut out = Vec::new();
+	let mut text = String::new();
+	let mut rest = raw;
+	while let Some(i) = rest.find('<') {
+		text.push_str(&rest[..i]);
+		rest = &rest[i..];
+		if let Some(comment) = rest.strip_prefix("<!--") {
+			rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+			continue
+		}
+		let closing = rest[1..].starts_with('/');
+		if !rest[1 + closing as usize..]
+			.starts_with(|c: char| c.is_ascii_alphabetic() || (!closing && (c == '!' || c == '?')))
+		{
+			// not a tag, so the `<` is just text
+			text.push('<');
+			rest = &rest[1..];
+			continue
+		}
+		let end = tag_end(rest);
+		let body_end = if rest[..end].ends_with('>') { end - 1 } else { end };
+		let (name, attrs) = parse_tag(&rest[1 + closing as usize..body_end]);
+		rest = &rest[end..];
+		if !text.is_empty() {
+			out.push(Token::Text(decode_entities(&std::mem::take(&mut text)).into_owned()))
+		}
+		if name.starts_with(['!', '?']) {
+			continue
+		}
+		if closing {
+			out.push(Token::End(name))
+		} else if name == "script" || name == "style" {
+			let close = format!("</{}", name);
+			rest = match rest.to_ascii_lowercase().find(&close) {
+				Some(end) => &rest[end + tag_end(&rest[end..])..],
+				None => "",
+			}
+		} else {
+			out.push(Token::Start { name, attrs })
+		}
+	}
+	text.push_str(rest);
+	if !text.is_empty() {
+		out.push(Token::Text(decode_entities(&text).into_owned()))
+	}
+	out
+}
+
+/// Parse the name and attributes of a tag without the `<` and `>`
+pub fn parse_tag(tag: &str) -> (String, Vec<(String, String)>) {
+	let tag = tag.trim_end_matches('/');
+	let name_end = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+	let mut attrs = Vec::new();
+	let mut iter = tag[name_end..].chars().peekable();
+	loop {
+		while iter.next_if(|c| c.is_whitespace() || *c == '/').is_some() {}
+		let mut key = String::new();
+		while let Some(c) = iter.next_if(|c| !c.is_whitespace() && *c != '=' && *c != '/') {
+			key.push(c.to_ascii_lowercase())
+		}
+		if key.is_empty() {
+			break
+		}
+		while iter.next_if(|c| c.is_whitespace()).is_some() {}
+		let mut value = String::new();
+		if iter.next_if_eq(&'=').is_some() {
+			while iter.next_if(|c| c.is_whitespace()).is_some() {}
+			match iter.next_if(|c| *c == '"' || *c == '\'') {
+				Some(quote) => {
+					for c in iter.by_ref() {
+						if c == quote {
+							break
+						}
+						value.push(c)
+					}
+				}
+				None => {
+					while let Some(c) = iter.next_if(|c| !c.is_whitespace()) {
+						value.push(c)
+					}
+				}
+			}
+		}
+		attrs.push((key, decode_entities(&value).into_owned()))
+	}
+	(tag[..name_end].to_ascii_lowercase(), attrs)
+}
+
+/// Get the length of a tag at the start of some text, skipping `>` in quoted attribute values
+fn tag_end(s: &str) -> usize {
+	let mut quote = None;
+	for (i, c) in s.char_indices() {
+		match (quote, c) {
+			(None, '"' | '\'') => quote = Some(c),
+			(Some(q), _) if q == c => quote = None,
+			(None, '>') => return i + 1,
+			_ => {}
+		}
+	}
+	s.len()
+}
+
+/// Parse the digits of a hex colour (`rrggbb` or `rgb`). An alpha channel is ignored
+pub fn parse_hex(hex: &str) -> Option<(u8, u8, u8)> {
+	let channel = |i: usize, len: usize| u8::from_str_radix(hex.get(i * len..(i + 1) * len)?, 16).ok();
+	match hex.len() {
+		6 | 8 => Some((channel(0, 2)?, channel(1, 2)?, channel(2, 2)?)),
+		3 | 4 => Some((channel(0, 1)? * 17, channel(1, 1)? * 17, channel(2, 1)? * 17)),
+		_ => None,
+	}
+}
+
+/// Parse a CSS colour. Supports hex colours, `rgb()`, and the basic named colours
+pub fn parse_colour(s: &str) -> Option<(u8, u8, u8)> {
+	let s = s.trim().to_ascii_lowercase();
+	if let Some(hex) = s.strip_prefix('#') {
+		return parse_hex(hex)
+	}
+	if let Some(args) = s.strip_prefix("rgb(").or_else(|| s.strip_prefix("rgba(")) {
+		let mut channels = args
+			.trim_end_matches(')')
+			.split([',', ' ', '/'])
+			.filter(|c| !c.is_empty())
+			.map(|c| match c.strip_suffix('%') {
+				Some(p) => p
+					.parse::<f64>()
+					.ok()
+					.map(|p| (p * 255.0 / 100.0).round().clamp(0.0, 255.0) as u8),
+				None => c.parse::<f64>().ok().map(|v| v.round().clamp(0.0, 255.0) as u8),
+			});
+		return Some((channels.next()??, channels.next()??, channels.next()??))
+	}
+	Some(match &*s {
+		"black" => (0, 0, 0),
+		"silver" => (192, 192, 192),
+		"gray" | "grey" => (128, 128, 128),
+		"white" => (255, 255, 255),
+		"maroon" => (128, 0, 0),
+		"red" => (255, 0, 0),
+		"purple" => (128, 0, 128),
+		"fuchsia" | "magenta" => (255, 0, 255),
+		"green" => (0, 128, 0),
+		"lime" => (0, 255, 0),
+		"olive" => (128, 128, 0),
+		"yellow" => (255, 255, 0),
+		"navy" => (0, 0, 128),
+		"blue" => (0, 0, 255),
+		"teal" => (0, 128, 128),
+		"aqua" | "cyan" => (0, 255, 255),
+		"orange" => (255, 165, 0),
+		"brown" => (165, 42, 42),
+		"pink" => (255, 192, 203),
+		"gold" => (255, 215, 0),
+		"darkred" => (139, 0, 0),
+		"darkgreen" => (0, 100, 0),
+		"darkblue" => (0, 0, 139),
+		"darkorange" => (255, 140, 0),
+		_ => return None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn start(name: &str, attrs: &[(&str, &str)]) -> Token {
+		Token::Start {
+			name: name.to_string(),
+			attrs: attrs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+		}
+	}
+
+	#[test]
+	fn named_entities() {
+		assert_eq!(decode_entities("a&nbsp;b"), "a\u{a0}b");
+		// only decoded once
+		assert_eq!(decode_entities("&amp;lt;"), "&lt;");
+		// legacy entities don't need a semicolon, and the longest name wins
+		assert_eq!(decode_entities("&notit;"), "\u{ac}it;");
+		assert_eq!(decode_entities("&notin;"), "\u{2209}");
+		assert_eq!(decode_entities("&unknown; &"), "&unknown; &");
+		assert_eq!(decode_entities("a & b &"), "a & b &");
+		assert!(matches!(decode_entities("plain"), Cow::Borrowed("plain")));
+	}
+
+	#[test]
+	fn numeric_entities() {
+		assert_eq!(decode_entities("&#65;&#x42;&#X43"), "ABC");
+		// C1 controls are read as Windows-1252
+		assert_eq!(decode_entities("&#x80;"), "\u{20ac}");
+		// NULL, surrogates and out of range values are replaced
+		assert_eq!(
+			decode_entities("&#0;&#xd800;&#x110000;&#99999999999;"),
+			"\u{fffd}".repeat(4)
+		);
+		// no digits isn't a reference
+		assert_eq!(decode_entities("&#;&#x;"), "&#;&#x;");
+	}
+
+	#[test]
+	fn tag_attributes() {
+		let (name, attrs) = parse_tag("SPAN Style = \"color: red\" class='a b' data-x=1 hidden/");
+		assert_eq!(name, "span");
+		assert_eq!(
+			attrs,
+			vec![
+				("style".to_string(), "color: red".to_string()),
+				("class".to_string(), "a b".to_string()),
+				("data-x".to_string(), "1".to_string()),
+				("hidden".to_string(), String::new()),
+			]
+		);
+		let (_, attrs) = parse_tag("a title=\"&lt;x&gt;\"");
+		assert_eq!(attrs[0].1, "<x>");
+	}
+
+	#[test]
+	fn tokenize() {
+		assert_eq!(
+			tokens("a < b <b class=\"x>y\">bold</B><!-- hidden --> &amp;<br/>"),
+			vec![
+				Token::Text("a < b ".to_string()),
+				start("b", &[("class", "x>y")]),
+				Token::Text("bold".to_string()),
+				Token::End("b".to_string()),
+				Token::Text(" &".to_string()),
+				start("br", &[]),
+			]
+		);
+		assert_eq!(
+			tokens("<!DOCTYPE html><script>if (a < b) {}</script>x<style>p {}"),
+			vec![Token::Text("x".to_string())]
+		);
+		assert_eq!(tokens("1 <2 <"), vec![Token::Text("1 <2 <".to_string())]);
+		assert!(tokens("<div class=\"warning note\">")[0].has_class("warning"));
+	}
+
+	#[test]
+	fn colours() {
+		assert_eq!(parse_colour("#ff8000"), Some((255, 128, 0)));
+		assert_eq!(parse_colour(" #F80 "), Some((255, 136, 0)));
+		assert_eq!(parse_colour("rgb(10, 20, 30)"), Some((10, 20, 30)));
+		assert_eq!(parse_colour("rgba(100% 0% 50% / 0.5)"), Some((255, 0, 128)));
+		assert_eq!(parse_colour("Grey"), Some((128, 128, 128)));
+		assert_eq!(parse_colour("#12345"), None);
+		assert_eq!(parse_colour("rgb(1, 2)"), None);
+		assert_eq!(parse_colour("notacolour"), None);
+		// config colours share the hex parsing, without the #
+		assert_eq!(parse_hex("ff800080"), Some((255, 128, 0)));
+		assert_eq!(parse_hex("#f80"), None);
+	}
+}
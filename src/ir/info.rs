@@ -0,0 +1,183 @@
+use std::ops::RangeInclusive;
+
+/// Options parsed from a code block info string. Supports mdBook style comma separated attributes
+/// (`rust,linenos=5`) and an optional trailing attribute block (`rust {linenos=5}`)
+#[derive(Debug, Default)]
+pub struct CodeInfo {
+	/// Language used for highlighting
+	pub language: Option<String>,
+	/// Per block line number setting. `None` uses the global config value
+	pub line_numbers: Option<bool>,
+	/// Number of the first line
+	pub line_start: Option<usize>,
+	/// Highlighted line ranges (`hl_lines="3-5 8"`). Lines are counted from the start of the block
+	/// starting at 1, regardless of the line number start
+	pub highlight_lines: Vec<RangeInclusive<usize>>,
+}
+
+impl CodeInfo {
+	/// Parse an info string
+	pub fn parse(info: &str) -> Self {
+		let mut out = Self::default();
+		let (head, block) = match info.find('{') {
+			Some(i) => (&info[..i], info[i + 1..].trim_end().trim_end_matches('}')),
+			None => (info, ""),
+		};
+		let mut attrs = head
+			.split(|c: char| c == ',' || c.is_whitespace())
+			.filter(|t| !t.is_empty())
+			.map(|t| match t.split_once('=') {
+				Some((k, v)) => (k.to_string(), Some(v.to_string())),
+				None => (t.to_string(), None),
+			})
+			.collect::<Vec<_>>();
+		if let Some((_, None)) = attrs.first() {
+			out.language = Some(attrs.remove(0).0)
+		}
+		attrs.extend(parse_attribute_block(block));
+		for (key, value) in attrs {
+			match (&*key, value) {
+				("linenos", None) => out.line_numbers = Some(true),
+				("linenos", Some(v)) => match (&*v, v.parse()) {
+					("true", _) => out.line_numbers = Some(true),
+					("false", _) => out.line_numbers = Some(false),
+					(_, Ok(start)) => {
+						out.line_numbers = Some(true);
+						out.line_start = Some(start)
+					}
+					_ => println!("Invalid linenos value in code block info string: {}", v),
+				},
+				("nolinenos", None) => out.line_numbers = Some(false),
+				("hl_lines", Some(v)) => out.highlight_lines.extend(parse_line_ranges(&v)),
+				(c, None) if c.starts_with('.') && out.language.is_none() => out.language = Some(c[1..].to_string()),
+				_ => {}
+			}
+		}
+		out
+	}
+}
+
+/// Parse the contents of a `{...}` attribute block into key-value pairs. Values can be quoted
+/// with single or double quotes to include whitespace
+fn parse_attribute_block(block: &str) -> Vec<(String, Option<String>)> {
+	let mut out = Vec::new();
+	let mut iter = block.chars().peekable();
+	loop {
+		while iter.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+		let mut key = String::new();
+		while let Some(c) = iter.next_if(|c| !c.is_whitespace() && *c != '=' && *c != ',') {
+			key.push(c)
+		}
+		if key.is_empty() {
+			break
+		}
+		if iter.next_if_eq(&'=').is_none() {
+			out.push((key, None));
+			continue
+		}
+		let mut value = String::new();
+		match iter.next_if(|c| *c == '"' || *c == '\'') {
+			Some(quote) => {
+				for c in iter.by_ref() {
+					if c == quote {
+						break
+					}
+					value.push(c)
+				}
+			}
+			None => {
+				while let Some(c) = iter.next_if(|c| !c.is_whitespace() && *c != ',') {
+					value.push(c)
+				}
+			}
+		}
+		out.push((key, Some(value)))
+	}
+	out
+}
+
+/// Parse a list of line ranges separated by whitespace or commas, e.g. `"3-5 8"`
+fn parse_line_ranges(raw: &str) -> Vec<RangeInclusive<usize>> {
+	let mut out = Vec::new();
+	for range in raw
+		.split(|c: char| c == ',' || c.is_whitespace())
+		.filter(|t| !t.is_empty())
+	{
+		let parsed = match range.split_once('-') {
+			Some((start, end)) => start.trim().parse().and_then(|s| end.trim().parse().map(|e| s..=e)),
+			None => range.parse().map(|l| l..=l),
+		};
+		match parsed {
+			Ok(r) => out.push(r),
+			Err(_) => println!("Invalid line range in code block info string: {}", range),
+		}
+	}
+	out
+}
+
+/// Split a Pandoc style attribute block (`{.rust}`) off the start of text following inline code.
+/// Returns `None` if the text doesn't start with an attribute block with a language
+pub fn inline_attributes(text: &str) -> Option<(CodeInfo, &str)> {
+	let block = text.strip_prefix('{')?;
+	let end = block.find('}')?;
+	let info = CodeInfo::parse(&text[..end + 2]);
+	info.language.as_ref()?;
+	Some((info, &block[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn language() {
+		assert_eq!(CodeInfo::parse("rust").language.as_deref(), Some("rust"));
+		assert_eq!(CodeInfo::parse("{.rust}").language.as_deref(), Some("rust"));
+		assert_eq!(CodeInfo::parse("python {.rust}").language.as_deref(), Some("python"));
+		assert_eq!(CodeInfo::parse("").language, None);
+		assert_eq!(CodeInfo::parse("linenos").language.as_deref(), Some("linenos"));
+	}
+
+	#[test]
+	fn line_numbers() {
+		let info = CodeInfo::parse("rust,linenos");
+		assert_eq!((info.line_numbers, info.line_start), (Some(true), None));
+		let info = CodeInfo::parse("rust {linenos=5}");
+		assert_eq!((info.line_numbers, info.line_start), (Some(true), Some(5)));
+		assert_eq!(CodeInfo::parse("rust,linenos=false").line_numbers, Some(false));
+		assert_eq!(CodeInfo::parse("rust nolinenos").line_numbers, Some(false));
+		assert_eq!(CodeInfo::parse("rust,linenos=x").line_numbers, None);
+		assert_eq!(CodeInfo::parse("rust").line_numbers, None);
+	}
+
+	#[test]
+	fn highlight_lines() {
+		let info = CodeInfo::parse("rust {hl_lines=\"3-5 8\"}");
+		assert_eq!(info.highlight_lines, vec![3..=5, 8..=8]);
+		let info = CodeInfo::parse("rust,hl_lines=2-3 {hl_lines='1, x, 7'}");
+		assert_eq!(info.highlight_lines, vec![2..=3, 1..=1, 7..=7]);
+	}
+
+	#[test]
+	fn malformed_attribute_block() {
+		// unterminated block and quote
+		let info = CodeInfo::parse("rust {linenos=2 hl_lines=\"4");
+		assert_eq!(info.language.as_deref(), Some("rust"));
+		assert_eq!(info.line_start, Some(2));
+		assert_eq!(info.highlight_lines, vec![4..=4]);
+		// stray separators and empty values
+		let info = CodeInfo::parse("{, =,linenos= }");
+		assert_eq!((info.language, info.line_numbers), (None, None));
+		let info = CodeInfo::parse("rust {}");
+		assert_eq!((info.language.as_deref(), info.line_numbers), (Some("rust"), None));
+	}
+
+	#[test]
+	fn inline() {
+		let (info, rest) = inline_attributes("{.rust} after").unwrap();
+		assert_eq!(info.language.as_deref(), Some("rust"));
+		assert_eq!(rest, " after");
+		assert!(inline_attributes("{linenos} after").is_none());
+		assert!(inline_attributes("{.rust").is_none());
+	}
+}
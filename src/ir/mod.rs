@@ -0,0 +1,148 @@
+//! Document model for book chapters. Chapters are parsed from Markdown into these blocks without
+//! anything specific to the PDF, and are then lowered into PDF elements by the build module
+
+mod info;
+mod parse;
+
+pub use info::{inline_attributes, CodeInfo};
+pub use parse::parse;
+
+/// Block level element of a chapter. Some details (like heading ids) aren't used by the PDF yet,
+/// but are kept so the model doesn't lose anything from the Markdown
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum Block {
+	/// Heading with a level from 1 to 6, and the id and classes from a `{#id .class}` attribute
+	/// block
+	Heading {
+		level: u8,
+		id: Option<String>,
+		classes: Vec<String>,
+		content: Vec<Inline>,
+	},
+	Paragraph(Vec<Inline>),
+	/// List with the number of the first item for ordered lists. Each item is a list of blocks, and
+	/// tight lists don't have space between their items
+	List {
+		start: Option<u64>,
+		tight: bool,
+		items: Vec<Vec<Block>>,
+	},
+	/// Code block with its info string (empty for indented code), the options parsed from it, and
+	/// its un-highlighted source
+	Code {
+		info_string: String,
+		info: CodeInfo,
+		source: String,
+	},
+	/// Block quote
+	Quote(Vec<Block>),
+	/// Raw HTML container (`<div>`, `<center>`, etc.)
+	Div(Vec<Block>),
+	/// mdBook warning callout (`<div class="warning">`)
+	Warning(Vec<Block>),
+	/// Raw HTML `<details>` element. The contents are always shown
+	Details {
+		summary: Vec<Inline>,
+		content: Vec<Block>,
+	},
+	/// Table with the alignment of each column, and header rows and body rows of cells. Column
+	/// widths can be given with a `<!-- compress-table widths="..." -->` comment before the table\
+	/// Cells are usually a single paragraph, but raw HTML can add more paragraphs, lists and code
+	Table {
+		alignments: Vec<Alignment>,
+		widths: Vec<ColumnWidth>,
+		head: Vec<Vec<Vec<Block>>>,
+		body: Vec<Vec<Vec<Block>>>,
+	},
+}
+
+/// Table column alignment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+	/// No alignment given, which is shown left aligned
+	None,
+	Left,
+	Center,
+	Right,
+}
+
+/// Table column width
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnWidth {
+	/// Sized from the contents of the column
+	Auto,
+	/// Percentage of the table width
+	Percent(f64),
+	/// Fixed width in millimeters
+	Mm(f64),
+}
+
+impl ColumnWidth {
+	/// Parse a column width. Either `auto`, a percentage (`25%`) or a length in millimeters
+	/// (`30mm`). Widths have to be more than 0
+	pub fn parse(s: &str) -> Self {
+		let positive = |v: &str| v.parse::<f64>().ok().filter(|v| v.is_finite() && *v > 0.0);
+		let width = match s.strip_suffix('%') {
+			Some(percent) => positive(percent).map(ColumnWidth::Percent),
+			None if s == "auto" => Some(ColumnWidth::Auto),
+			None => s.strip_suffix("mm").and_then(positive).map(ColumnWidth::Mm),
+		};
+		width.unwrap_or_else(|| {
+			println!("Invalid table column width \"{}\". Using auto", s);
+			ColumnWidth::Auto
+		})
+	}
+}
+
+/// Inline element of a paragraph. Link and image targets aren't used by the PDF yet, but are kept
+/// for the same reason
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum Inline {
+	Text(String),
+	Strong(Vec<Inline>),
+	Emphasis(Vec<Inline>),
+	/// Inline code with the language given in an attribute block after it
+	Code {
+		code: String,
+		language: Option<String>,
+	},
+	Link {
+		url: String,
+		title: String,
+		content: Vec<Inline>,
+	},
+	/// Image with its alt text
+	Image {
+		url: String,
+		title: String,
+		alt: Vec<Inline>,
+	},
+	/// Text styled with raw HTML (`<span style="color: red">`)
+	Styled {
+		colour: Option<(u8, u8, u8)>,
+		bold: bool,
+		content: Vec<Inline>,
+	},
+	/// Line break in the source that isn't a hard break. Shown as a space
+	SoftBreak,
+	/// Forced line break (a backslash or two spaces at the end of a line)
+	HardBreak,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn column_widths() {
+		assert_eq!(ColumnWidth::parse("auto"), ColumnWidth::Auto);
+		assert_eq!(ColumnWidth::parse("25%"), ColumnWidth::Percent(25.0));
+		assert_eq!(ColumnWidth::parse("30.5mm"), ColumnWidth::Mm(30.5));
+		// zero, negative and unknown widths are auto
+		for width in ["0mm", "-5%", "0%", "NaN%", "inf mm", "10", "wide"] {
+			assert_eq!(ColumnWidth::parse(width), ColumnWidth::Auto, "{}", width)
+		}
+	}
+}
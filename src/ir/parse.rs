@@ -0,0 +1,636 @@
+use std::iter::Peekable;
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+
+use crate::{
+	html::{self, Token},
+	ir::{inline_attributes, Alignment, Block, CodeInfo, ColumnWidth, Inline},
+};
+
+/// Markdown events being parsed
+type Events<'a> = Peekable<Parser<'a, 'a>>;
+
+/// Raw HTML inline elements. The contents of other inline elements are kept without a style
+const INLINE_ELEMENTS: &[&str] = &[
+	"a", "abbr", "b", "cite", "code", "del", "em", "font", "i", "ins", "kbd", "mark", "q", "s", "samp", "small",
+	"span", "strong", "sub", "sup", "u", "var",
+];
+
+/// Raw HTML block elements used as containers
+const CONTAINER_ELEMENTS: &[&str] = &["article", "aside", "center", "details", "div", "section"];
+
+/// Raw HTML elements that make blocks in table cells
+const CELL_ELEMENTS: &[&str] = &["li", "ol", "pre", "ul"];
+
+/// Parse a chapter into blocks
+pub fn parse(chapter: &str) -> Vec<Block> {
+	blocks(&mut Parser::new_ext(chapter, Options::all()).peekable(), &mut false)
+}
+
+/// Parse blocks up to and including the end of the parent element. Inline elements between blocks
+/// (in tight list items) are put into paragraphs. `loose` is set if there are paragraphs in the
+/// Markdown, which is how list items in loose lists are given\
+/// Raw HTML container elements (like `<div>`) can have Markdown blocks between their start and end
+/// tags, so the open containers are kept on a stack, with the parent element at the bottom
+fn blocks(events: &mut Events, loose: &mut bool) -> Vec<Block> {
+	let mut containers = vec![Container::new(String::new(), None)];
+	let mut para = Inlines::default();
+	// column widths from a comment for the next table
+	let mut table_widths = None;
+	loop {
+		match events.next() {
+			None | Some(Event::End(_)) => break,
+			Some(Event::Start(tag)) if is_block(&tag) => {
+				let blocks = &mut containers.last_mut().unwrap().blocks;
+				para.flush(blocks);
+				*loose |= tag == Tag::Paragraph;
+				let widths = table_widths.take();
+				let mut block = block(tag, events);
+				if let Some(Block::Table { widths: table, .. }) = &mut block {
+					*table = widths.unwrap_or_default()
+				}
+				blocks.extend(block)
+			}
+			// HTML blocks are given line by line
+			Some(Event::Html(raw)) if raw.ends_with('\n') => {
+				if let Some(widths) = table_comment(&raw) {
+					table_widths = Some(widths);
+					continue
+				}
+				for token in html::tokens(&raw) {
+					block_html(token, &mut containers, &mut para)
+				}
+			}
+			Some(event) => inline(event, events, &mut para),
+		}
+	}
+	para.flush(&mut containers.last_mut().unwrap().blocks);
+	close_containers(&mut containers, 1);
+	containers.pop().unwrap().blocks
+}
+
+/// Raw HTML container element being parsed
+struct Container {
+	/// Tag name
+	name: String,
+	/// Start tag. `None` for the parent element the blocks are in
+	start: Option<Token>,
+	/// `<summary>` of a `<details>` element
+	summary: Vec<Inline>,
+	blocks: Vec<Block>,
+}
+
+impl Container {
+	fn new(name: String, start: Option<Token>) -> Self {
+		Self {
+			name,
+			start,
+			summary: Vec::new(),
+			blocks: Vec::new(),
+		}
+	}
+
+	/// Turn the container into a block
+	fn into_block(self) -> Block {
+		match self.start {
+			Some(start) if start.has_class("warning") => Block::Warning(self.blocks),
+			// list items are divs, which are turned into items when the list is closed
+			Some(start) if self.name == "ol" || self.name == "ul" => Block::List {
+				start: (self.name == "ol").then(|| start.attr("start").and_then(|s| s.parse().ok()).unwrap_or(1)),
+				tight: true,
+				items: self
+					.blocks
+					.into_iter()
+					.map(|block| match block {
+						Block::Div(item) => item,
+						block => vec![block],
+					})
+					.collect(),
+			},
+			Some(start) if self.name == "pre" => {
+				let mut source = String::new();
+				for (i, block) in self.blocks.iter().enumerate() {
+					if let Block::Paragraph(content) = block {
+						if i > 0 {
+							source.push('\n')
+						}
+						plain_text(content, &mut source)
+					}
+				}
+				let language = start
+					.attr("class")
+					.unwrap_or_default()
+					.split_whitespace()
+					.find_map(|c| c.strip_prefix("language-"))
+					.unwrap_or_default();
+				Block::Code {
+					info: CodeInfo::parse(language),
+					info_string: language.to_string(),
+					source,
+				}
+			}
+			_ if self.name == "details" => Block::Details {
+				summary: self.summary,
+				content: self.blocks,
+			},
+			_ => Block::Div(self.blocks),
+		}
+	}
+}
+
+/// Handle a raw HTML token between blocks
+fn block_html(token: Token, containers: &mut Vec<Container>, para: &mut Inlines) {
+	let blocks = &mut containers.last_mut().unwrap().blocks;
+	match &token {
+		Token::Start { name, .. } if CONTAINER_ELEMENTS.contains(&name.as_str()) => {
+			para.flush(blocks);
+			containers.push(Container::new(name.clone(), Some(token)))
+		}
+		Token::End(name) if CONTAINER_ELEMENTS.contains(&name.as_str()) => {
+			para.flush(blocks);
+			if let Some(i) = containers.iter().skip(1).rposition(|c| &c.name == name) {
+				close_containers(containers, i + 1)
+			}
+		}
+		// the summary is collected like a paragraph, then given to the `<details>` element
+		Token::Start { name, .. } if name == "summary" => para.flush(blocks),
+		Token::End(name) if name == "summary" => {
+			let summary = std::mem::take(para).finish();
+			match containers.last_mut() {
+				Some(container) if container.name == "details" => container.summary = summary,
+				Some(container) => container.blocks.push(Block::Paragraph(vec![Inline::Strong(summary)])),
+				None => {}
+			}
+		}
+		Token::Start { name, .. } | Token::End(name) if matches!(name.as_str(), "p" | "hr") => para.flush(blocks),
+		Token::Text(t) if para.is_empty() && t.trim().is_empty() => {}
+		_ => para.html(token),
+	}
+}
+
+/// Handle a raw HTML token in a table cell
+fn cell_html(token: Token, containers: &mut Vec<Container>, para: &mut Inlines) {
+	let in_pre = containers.last().is_some_and(|c| c.name == "pre");
+	let blocks = &mut containers.last_mut().unwrap().blocks;
+	match &token {
+		Token::Start { name, .. } if CELL_ELEMENTS.contains(&name.as_str()) => {
+			para.flush(blocks);
+			containers.push(Container::new(name.clone(), Some(token)))
+		}
+		Token::End(name) if CELL_ELEMENTS.contains(&name.as_str()) => {
+			para.flush(blocks);
+			if let Some(i) = containers.iter().skip(1).rposition(|c| &c.name == name) {
+				close_containers(containers, i + 1)
+			}
+		}
+		// the `<code>` tag in a `<pre>` element gives the language, so it's kept as the start tag
+		Token::Start { name, .. } if name == "code" && in_pre => containers.last_mut().unwrap().start = Some(token),
+		Token::Start { name, .. } | Token::End(name) if name == "p" => para.flush(blocks),
+		Token::Text(t) if para.is_empty() && t.trim().is_empty() => {}
+		_ => para.html(token),
+	}
+}
+
+/// Add the text of inline elements to a string. Line breaks are newlines
+fn plain_text(content: &[Inline], out: &mut String) {
+	for inline in content {
+		match inline {
+			Inline::Text(t) => out.push_str(t),
+			Inline::Code { code, .. } => out.push_str(code),
+			Inline::Strong(c)
+			| Inline::Emphasis(c)
+			| Inline::Link { content: c, .. }
+			| Inline::Image { alt: c, .. }
+			| Inline::Styled { content: c, .. } => plain_text(c, out),
+			Inline::SoftBreak | Inline::HardBreak => out.push('\n'),
+		}
+	}
+}
+
+/// Close raw HTML containers down to a depth, adding each to its parent
+fn close_containers(containers: &mut Vec<Container>, depth: usize) {
+	while containers.len() > depth.max(1) {
+		let block = containers.pop().unwrap().into_block();
+		containers.last_mut().unwrap().blocks.push(block)
+	}
+}
+
+/// Checks if a tag starts a block element
+fn is_block(tag: &Tag) -> bool {
+	matches!(
+		tag,
+		Tag::Paragraph
+			| Tag::Heading(..)
+			| Tag::BlockQuote
+			| Tag::CodeBlock(_)
+			| Tag::List(_)
+			| Tag::FootnoteDefinition(_)
+			| Tag::Table(_)
+	)
+}
+
+/// Parse a block element after its start tag. Returns `None` for unsupported elements, which are
+/// skipped
+fn block(tag: Tag, events: &mut Events) -> Option<Block> {
+	Some(match tag {
+		Tag::Paragraph => Block::Paragraph(inlines(events)),
+		Tag::Heading(level, id, classes) => Block::Heading {
+			level: level as u8,
+			id: id.map(|id| id.to_string()),
+			classes: classes.into_iter().map(|c| c.to_string()).collect(),
+			content: inlines(events),
+		},
+		Tag::List(start) => {
+			let mut items = Vec::new();
+			let mut loose = false;
+			while let Some(Event::Start(Tag::Item)) = events.next() {
+				items.push(blocks(events, &mut loose))
+			}
+			Block::List {
+				start,
+				tight: !loose,
+				items,
+			}
+		}
+		Tag::CodeBlock(kind) => {
+			let info_string = match kind {
+				CodeBlockKind::Fenced(info) => info.to_string(),
+				CodeBlockKind::Indented => String::new(),
+			};
+			let mut source = String::new();
+			while let Some(Event::Text(t)) = events.next() {
+				source.push_str(&t)
+			}
+			Block::Code {
+				info: CodeInfo::parse(&info_string),
+				info_string,
+				source,
+			}
+		}
+		Tag::BlockQuote => Block::Quote(blocks(events, &mut false)),
+		Tag::Table(alignments) => {
+			let mut head = Vec::new();
+			let mut body = Vec::new();
+			loop {
+				match events.next() {
+					Some(Event::Start(Tag::TableHead)) => head.push(table_row(events)),
+					Some(Event::Start(Tag::TableRow)) => body.push(table_row(events)),
+					_ => break,
+				}
+			}
+			Block::Table {
+				widths: Vec::new(),
+				alignments: alignments
+					.into_iter()
+					.map(|a| match a {
+						pulldown_cmark::Alignment::None => Alignment::None,
+						pulldown_cmark::Alignment::Left => Alignment::Left,
+						pulldown_cmark::Alignment::Center => Alignment::Center,
+						pulldown_cmark::Alignment::Right => Alignment::Right,
+					})
+					.collect(),
+				head,
+				body,
+			}
+		}
+		_ => {
+			skip(events);
+			return None
+		}
+	})
+}
+
+/// Get the column widths from a `<!-- compress-table widths="..." -->` comment before a table.
+/// Widths are separated by spaces
+fn table_comment(raw: &str) -> Option<Vec<ColumnWidth>> {
+	let comment = raw.trim().strip_prefix("<!--")?.strip_suffix("-->")?;
+	let (name, attrs) = html::parse_tag(comment.trim());
+	if name != "compress-table" {
+		return None
+	}
+	Some(
+		attrs
+			.iter()
+			.find(|(k, _)| k == "widths")
+			.map(|(_, widths)| widths.split_whitespace().map(ColumnWidth::parse).collect())
+			.unwrap_or_default(),
+	)
+}
+
+/// Parse the cells of a table row
+fn table_row(events: &mut Events) -> Vec<Vec<Block>> {
+	let mut out = Vec::new();
+	while let Some(Event::Start(Tag::TableCell)) = events.next() {
+		out.push(table_cell(events))
+	}
+	out
+}
+
+/// Parse a table cell. Markdown only has inline elements in cells, but raw HTML paragraphs, lists
+/// and `<pre>` code blocks are made into blocks
+fn table_cell(events: &mut Events) -> Vec<Block> {
+	let mut containers = vec![Container::new(String::new(), None)];
+	let mut para = Inlines::default();
+	loop {
+		match events.next() {
+			None | Some(Event::End(_)) => break,
+			Some(Event::Html(raw)) => {
+				for token in html::tokens(&raw) {
+					cell_html(token, &mut containers, &mut para)
+				}
+			}
+			Some(event) => inline(event, events, &mut para),
+		}
+	}
+	para.flush(&mut containers.last_mut().unwrap().blocks);
+	close_containers(&mut containers, 1);
+	containers.pop().unwrap().blocks
+}
+
+/// Parse inline elements up to and including the end of the parent element
+fn inlines(events: &mut Events) -> Vec<Inline> {
+	let mut out = Inlines::default();
+	loop {
+		match events.next() {
+			None | Some(Event::End(_)) => break,
+			Some(event) => inline(event, events, &mut out),
+		}
+	}
+	out.finish()
+}
+
+/// Inline elements being parsed. Raw HTML inline elements can start and end in different events,
+/// so open elements are kept on a stack with their contents
+#[derive(Default)]
+struct Inlines {
+	out: Vec<Inline>,
+	/// Open raw HTML elements with their tag name and start tag
+	open: Vec<(String, Token, Vec<Inline>)>,
+}
+
+impl Inlines {
+	/// Add an inline element
+	fn push(&mut self, inline: Inline) {
+		match self.open.last_mut() {
+			Some((.., content)) => content.push(inline),
+			None => self.out.push(inline),
+		}
+	}
+
+	/// Checks if there's nothing in the inline elements yet
+	fn is_empty(&self) -> bool { self.out.is_empty() && self.open.iter().all(|(.., c)| c.is_empty()) }
+
+	/// Handle a raw HTML token. Block elements are ignored
+	fn html(&mut self, token: Token) {
+		match token {
+			Token::Text(t) => {
+				// whitespace in HTML is collapsed to a single space
+				let mut text = t.split_whitespace().collect::<Vec<_>>().join(" ");
+				if t.starts_with(char::is_whitespace) && !self.is_empty() {
+					text.insert(0, ' ')
+				}
+				if t.ends_with(char::is_whitespace) && !text.is_empty() {
+					text.push(' ')
+				}
+				if !text.is_empty() {
+					self.push(Inline::Text(text))
+				}
+			}
+			Token::Start { ref name, .. } if name == "br" => self.push(Inline::HardBreak),
+			Token::Start { ref name, .. } if name == "img" => self.push(Inline::Image {
+				url: token.attr("src").unwrap_or_default().to_string(),
+				title: token.attr("title").unwrap_or_default().to_string(),
+				alt: vec![Inline::Text(token.attr("alt").unwrap_or_default().to_string())],
+			}),
+			Token::Start { ref name, .. } if INLINE_ELEMENTS.contains(&name.as_str()) => {
+				self.open.push((name.clone(), token, Vec::new()))
+			}
+			Token::End(name) => {
+				if let Some(i) = self.open.iter().rposition(|(n, ..)| *n == name) {
+					self.close(i)
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// Close open raw HTML elements down to a depth, adding each to its parent
+	fn close(&mut self, depth: usize) {
+		while self.open.len() > depth {
+			let (name, start, content) = self.open.pop().unwrap();
+			match &*name {
+				"b" | "strong" => self.push(Inline::Strong(content)),
+				"i" | "em" => self.push(Inline::Emphasis(content)),
+				_ => match inline_style(&start) {
+					(None, false) => {
+						for inline in content {
+							self.push(inline)
+						}
+					}
+					(colour, bold) => self.push(Inline::Styled { colour, bold, content }),
+				},
+			}
+		}
+	}
+
+	/// Put the inline elements into a paragraph, if there are any
+	fn flush(&mut self, blocks: &mut Vec<Block>) {
+		let content = std::mem::take(self).finish();
+		if !content.is_empty() {
+			blocks.push(Block::Paragraph(content))
+		}
+	}
+
+	/// Get the inline elements, closing any open raw HTML elements
+	fn finish(mut self) -> Vec<Inline> {
+		self.close(0);
+		self.out
+	}
+}
+
+/// Get the text colour and weight of a raw HTML inline element from its `style` attribute (or the
+/// `color` attribute of `<font>`)
+fn inline_style(start: &Token) -> (Option<(u8, u8, u8)>, bool) {
+	let mut colour = start.attr("color").and_then(html::parse_colour);
+	let mut bold = false;
+	for (property, value) in start
+		.attr("style")
+		.unwrap_or_default()
+		.split(';')
+		.filter_map(|p| p.split_once(':'))
+	{
+		let value = value.trim();
+		match &*property.trim().to_ascii_lowercase() {
+			"color" => colour = html::parse_colour(value).or(colour),
+			"font-weight" => bold = matches!(value, "bold" | "bolder") || value.parse::<u16>().is_ok_and(|w| w >= 600),
+			_ => {}
+		}
+	}
+	(colour, bold)
+}
+
+/// Parse an inline element. Unsupported elements keep their contents
+fn inline(event: Event, events: &mut Events, out: &mut Inlines) {
+	match event {
+		Event::Text(t) => out.push(Inline::Text(t.to_string())),
+		Event::Code(code) => {
+			// an attribute block straight after inline code gives its language, and is removed from
+			// the text after the code
+			let (language, rest) = match events.peek() {
+				Some(Event::Text(t)) => match inline_attributes(t) {
+					Some((info, rest)) => (info.language, Some(rest.to_string())),
+					None => (None, None),
+				},
+				_ => (None, None),
+			};
+			if rest.is_some() {
+				events.next();
+			}
+			out.push(Inline::Code {
+				code: code.to_string(),
+				language,
+			});
+			if let Some(rest) = rest.filter(|r| !r.is_empty()) {
+				out.push(Inline::Text(rest))
+			}
+		}
+		Event::Html(raw) => {
+			for token in html::tokens(&raw) {
+				out.html(token)
+			}
+		}
+		Event::SoftBreak => out.push(Inline::SoftBreak),
+		Event::HardBreak => out.push(Inline::HardBreak),
+		Event::Start(Tag::Strong) => out.push(Inline::Strong(inlines(events))),
+		Event::Start(Tag::Emphasis) => out.push(Inline::Emphasis(inlines(events))),
+		Event::Start(Tag::Link(_, url, title)) => out.push(Inline::Link {
+			url: url.to_string(),
+			title: title.to_string(),
+			content: inlines(events),
+		}),
+		Event::Start(Tag::Image(_, url, title)) => out.push(Inline::Image {
+			url: url.to_string(),
+			title: title.to_string(),
+			alt: inlines(events),
+		}),
+		Event::Start(_) => {
+			for inline in inlines(events) {
+				out.push(inline)
+			}
+		}
+		_ => {}
+	}
+}
+
+/// Skip events up to and including the end of the parent element
+fn skip(events: &mut Events) {
+	let mut depth = 0;
+	for event in events {
+		match event {
+			Event::Start(_) => depth += 1,
+			Event::End(_) if depth == 0 => break,
+			Event::End(_) => depth -= 1,
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Text of a single text inline
+	fn text(inline: &Inline) -> &str {
+		match inline {
+			Inline::Text(text) => text,
+			other => panic!("expected text, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn paragraphs() {
+		let blocks = parse("Some *emphasis* and **strong** text\n\nSecond `code`{.rust}");
+		let [Block::Paragraph(first), Block::Paragraph(second)] = &blocks[..] else {
+			panic!("expected two paragraphs, got {:?}", blocks)
+		};
+		assert_eq!(text(&first[0]), "Some ");
+		assert!(matches!(&first[1], Inline::Emphasis(content) if text(&content[0]) == "emphasis"));
+		assert!(matches!(&first[3], Inline::Strong(content) if text(&content[0]) == "strong"));
+		assert!(matches!(
+			&second[1],
+			Inline::Code { code, language: Some(language) } if code == "code" && language == "rust"
+		));
+		// the attribute block is removed from the text after the code
+		assert_eq!(second.len(), 2);
+	}
+
+	#[test]
+	fn breaks() {
+		let blocks = parse("one\ntwo\\\nthree  \nfour");
+		let [Block::Paragraph(content)] = &blocks[..] else {
+			panic!("expected a paragraph, got {:?}", blocks)
+		};
+		assert!(matches!(content[1], Inline::SoftBreak));
+		assert!(matches!(content[3], Inline::HardBreak));
+		assert!(matches!(content[5], Inline::HardBreak));
+		assert_eq!(text(&content[6]), "four");
+	}
+
+	#[test]
+	fn lists() {
+		let blocks = parse("3. one\n4. two\n   - nested\n\n- loose\n\n- list\n");
+		let [Block::List {
+			start: Some(3),
+			tight: true,
+			items,
+		}, Block::List {
+			start: None,
+			tight: false,
+			items: loose,
+		}] = &blocks[..]
+		else {
+			panic!("expected two lists, got {:?}", blocks)
+		};
+		assert_eq!(items.len(), 2);
+		assert!(matches!(&items[0][..], [Block::Paragraph(content)] if text(&content[0]) == "one"));
+		assert!(matches!(
+			&items[1][..],
+			[Block::Paragraph(_), Block::List { start: None, items, .. }] if items.len() == 1
+		));
+		assert_eq!(loose.len(), 2);
+	}
+
+	#[test]
+	fn tables() {
+		let blocks =
+			parse("<!-- compress-table widths=\"30% auto\" -->\n\n| a | b |\n|:--|--:|\n| 1 | 2 |\n| 3 | 4 |\n");
+		let [Block::Table {
+			alignments,
+			widths,
+			head,
+			body,
+		}] = &blocks[..]
+		else {
+			panic!("expected a table, got {:?}", blocks)
+		};
+		assert_eq!(alignments, &[Alignment::Left, Alignment::Right]);
+		assert_eq!(widths, &[ColumnWidth::Percent(30.0), ColumnWidth::Auto]);
+		assert_eq!(head.len(), 1);
+		assert_eq!(body.len(), 2);
+		assert!(matches!(&body[1][0][..], [Block::Paragraph(content)] if text(&content[0]) == "3"));
+	}
+
+	#[test]
+	fn html_containers() {
+		let blocks = parse(
+			"<div class=\"warning\">\n\nCareful\n\n</div>\n\n<details>\n<summary>More</summary>\n\nHidden\n\n</details>\n\n<center>\n\nMiddle\n\n</center>\n",
+		);
+		let [Block::Warning(warning), Block::Details { summary, content }, Block::Div(div)] = &blocks[..] else {
+			panic!("expected a warning, details and div, got {:?}", blocks)
+		};
+		assert!(matches!(&warning[..], [Block::Paragraph(content)] if text(&content[0]) == "Careful"));
+		assert_eq!(text(&summary[0]), "More");
+		assert!(matches!(&content[..], [Block::Paragraph(content)] if text(&content[0]) == "Hidden"));
+		assert!(matches!(&div[..], [Block::Paragraph(content)] if text(&content[0]) == "Middle"));
+	}
+}
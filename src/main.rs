@@ -1,6 +1,9 @@
 mod build;
 mod config;
 mod highlight;
+mod html;
+mod ir;
+mod theme;
 
 use anyhow::Error;
 use mdbook::renderer::RenderContext;
@@ -9,15 +12,19 @@ use crate::{build::Generator, config::Config};
 
 fn main() {
 	let rc = RenderContext::from_json(&mut std::io::stdin()).unwrap();
-	let opts = match rc.config.get_deserialized_opt::<Config, _>("output.compress") {
-		Ok(t) => Config::from_rc(t),
-		Err(e) => {
-			let err = Error::msg(format!("Unable to parse config config file: {}", e));
-			mdbook::utils::log_backtrace(&err);
-			return
-		}
+	let config = theme::config(&rc.root, rc.config.get("output.compress")).unwrap_or_else(|e| fail(e));
+	let opts = match config.try_into::<Config>() {
+		Ok(t) => t,
+		Err(e) => fail(Error::msg(format!("Unable to parse config config file: {}", e))),
 	};
 	if let Err(e) = Generator::new(rc, opts).build() {
-		mdbook::utils::log_backtrace(&Error::new(e));
+		fail(Error::new(e))
 	}
 }
+
+/// Report an error and stop. mdbook shows what renderers write to stderr, and fails the build when
+/// one exits with an error
+fn fail(err: Error) -> ! {
+	eprintln!("mdbook-compress: {:#}", err);
+	std::process::exit(1)
+}
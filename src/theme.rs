@@ -0,0 +1,171 @@
+//! Themes. A theme sets the default styles of a book, and is made of up to three layers, each one
+//! overriding the one before: a built-in preset chosen with `theme = "..."`, a
+//! `theme/compress.toml` file in the book, and the `[output.compress]` config itself
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use toml::{value::Table, Value};
+
+/// Built-in presets
+const PRESETS: &[(&str, &str)] = &[
+	("classic", include_str!("../theme/presets/classic.toml")),
+	("modern", include_str!("../theme/presets/modern.toml")),
+	("compact", include_str!("../theme/presets/compact.toml")),
+	("print-bw", include_str!("../theme/presets/print-bw.toml")),
+];
+
+/// Config keys that a theme file can set. Anything else in a theme file is ignored
+const THEME_KEYS: &[&str] = &[
+	"font_size",
+	"page",
+	"print-mode",
+	"text",
+	"link",
+	"quote",
+	"style",
+	"code",
+	"table",
+];
+
+/// Get the `[output.compress]` config with the theme layers under it. Fails if the preset doesn't
+/// exist or `theme/compress.toml` isn't valid TOML
+pub fn config(root: &Path, book: Option<&Value>) -> Result<Value> {
+	let name = match book.and_then(|book| book.get("theme")) {
+		Some(Value::String(name)) => name.as_str(),
+		Some(value) => bail!("output.compress.theme should be the name of a preset, not {}", value),
+		None => "classic",
+	};
+	let Some((_, preset)) = PRESETS.iter().find(|(preset, _)| *preset == name) else {
+		let names = PRESETS
+			.iter()
+			.map(|(name, _)| format!("\"{}\"", name))
+			.collect::<Vec<_>>();
+		bail!(
+			"Unknown theme \"{}\" in output.compress.theme. Expected one of {}",
+			name,
+			names.join(", ")
+		)
+	};
+	let mut out = theme(preset.parse().expect("built-in themes are valid TOML"), "the preset");
+	if let Ok(raw) = std::fs::read_to_string(root.join("theme").join("compress.toml")) {
+		let file = raw.parse().context("Unable to parse theme/compress.toml")?;
+		merge(&mut out, theme(file, "theme/compress.toml"))
+	}
+	if let Some(book) = book {
+		merge(&mut out, book.clone())
+	}
+	Ok(out)
+}
+
+/// Keep only the theme keys of a theme
+fn theme(mut value: Value, source: &str) -> Value {
+	if let Value::Table(table) = &mut value {
+		let mut out = Table::new();
+		for (key, value) in std::mem::take(table) {
+			if THEME_KEYS.contains(&key.as_str()) {
+				out.insert(key, value);
+			} else {
+				eprintln!(
+					"mdbook-compress: ignoring \"{}\" in {}. Themes can only set styles",
+					key, source
+				)
+			}
+		}
+		*table = out
+	}
+	value
+}
+
+/// Merge a value over another. Tables are merged key by key, and anything else is replaced
+fn merge(base: &mut Value, over: Value) {
+	match (base, over) {
+		(Value::Table(base), Value::Table(over)) => {
+			for (key, value) in over {
+				match base.get_mut(&key) {
+					Some(existing) => merge(existing, value),
+					None => {
+						base.insert(key, value);
+					}
+				}
+			}
+		}
+		(base, over) => *base = over,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn toml(raw: &str) -> Value { raw.parse().unwrap() }
+
+	/// Book root with an optional `theme/compress.toml`
+	fn root(name: &str, theme: Option<&str>) -> std::path::PathBuf {
+		let root = std::env::temp_dir().join(format!("mdbook-compress-theme-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&root);
+		std::fs::create_dir_all(root.join("theme")).unwrap();
+		if let Some(theme) = theme {
+			std::fs::write(root.join("theme").join("compress.toml"), theme).unwrap()
+		}
+		root
+	}
+
+	#[test]
+	fn merge_tables() {
+		let mut base = toml("a = 1\nlist = [1, 2]\n[t]\nx = 1\ny = 2\n[t.inner]\nz = 3");
+		merge(
+			&mut base,
+			toml("b = 2\nlist = [3]\n[t]\ny = \"over\"\n[t.inner]\nw = 4"),
+		);
+		// tables are merged all the way down, and other values (including arrays) are replaced
+		assert_eq!(
+			base,
+			toml("a = 1\nb = 2\nlist = [3]\n[t]\nx = 1\ny = \"over\"\n[t.inner]\nz = 3\nw = 4")
+		);
+		let mut base = toml("[t]\nx = 1");
+		merge(&mut base, toml("t = 5"));
+		assert_eq!(base, toml("t = 5"));
+	}
+
+	#[test]
+	fn theme_keys() {
+		let value = theme(
+			toml("title = \"x\"\njobs = 2\nprint-mode = \"grayscale\"\n[link]\ncolour = \"#fff\"\n[highlight]\nx = 1"),
+			"theme/compress.toml",
+		);
+		assert_eq!(value, toml("print-mode = \"grayscale\"\n[link]\ncolour = \"#fff\""));
+	}
+
+	#[test]
+	fn layers() {
+		let dir = root(
+			"layers",
+			Some("subtitle = \"ignored\"\n[link]\ncolour = \"#ff0000\"\n[text]\ncolour = \"#00ff00\""),
+		);
+		let book = toml("theme = \"modern\"\n[text]\ncolour = \"#0000ff\"");
+		let layered = config(&dir, Some(&book)).unwrap();
+		// the preset is under compress.toml, which is under the book config
+		assert_eq!(layered["code"]["border"], Value::Boolean(true));
+		assert_eq!(layered["link"]["colour"].as_str(), Some("#ff0000"));
+		assert_eq!(layered["text"]["colour"].as_str(), Some("#0000ff"));
+		assert_eq!(layered["theme"].as_str(), Some("modern"));
+		assert!(layered.get("subtitle").is_none());
+		// classic is the default
+		let layered = config(&dir, None).unwrap();
+		assert!(layered.get("code").is_none());
+		assert_eq!(layered["link"]["colour"].as_str(), Some("#ff0000"));
+		let _ = std::fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn invalid_themes() {
+		let dir = root("invalid", Some("[link"));
+		assert!(config(&dir, None).is_err());
+		let dir = root("invalid", None);
+		assert!(config(&dir, Some(&toml("theme = \"fancy\""))).is_err());
+		assert!(config(&dir, Some(&toml("theme = 3"))).is_err());
+		assert!(config(&dir, Some(&toml("theme = \"print-bw\""))).is_ok());
+		let _ = std::fs::remove_dir_all(dir);
+	}
+}